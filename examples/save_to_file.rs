@@ -1,4 +1,6 @@
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use clap::Parser;
+use serde::Deserialize;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use zenoh::config::Config as ZenohConfig;
@@ -8,10 +10,31 @@ use zenoh::prelude::r#async::*;
 #[command(author, version)]
 struct Args {
     /// Topic to listen on
-    #[arg(long, default_value = "wakeword/event/wake_word_audio_wav")]
+    #[arg(long, default_value = "wakeword/event/wake_word_audio")]
     topic: String,
 }
 
+/// Subset of the audio recording message published by wakeword
+#[derive(Deserialize, Debug)]
+struct AudioRecording {
+    recording_id: String,
+    wake_word: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    duration_ms: u64,
+    direction_of_arrival: Option<i32>,
+    format: String,
+    audio_base64: String,
+}
+
+impl AudioRecording {
+    fn file_extension(&self) -> &str {
+        match self.format.as_str() {
+            "ogg_opus" => "ogg",
+            format => format,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Args = Args::parse();
@@ -27,17 +50,29 @@ async fn main() -> anyhow::Result<()> {
         .await
         .unwrap();
 
-    let mut counter = 0;
     loop {
         println!("Waiting for message");
         let msg = file_subscriber.recv_async().await.unwrap();
-        println!("Received new file");
-        let msg: Vec<u8> = msg.value.try_into()?;
-        let filename = format!("tmp/recording_{}.wav", counter);
+        let msg: String = msg.value.try_into()?;
+        let recording: AudioRecording = serde_json::from_str(&msg)?;
+        println!(
+            "Received recording {} for {:?} lasting {}ms from direction {:?}",
+            recording.recording_id,
+            recording.wake_word,
+            recording.duration_ms,
+            recording.direction_of_arrival
+        );
+        let audio_file = BASE64_STANDARD.decode(&recording.audio_base64)?;
+        let filename = format!(
+            "tmp/{}_{}_{}.{}",
+            recording.timestamp.format("%Y-%m-%d_%H-%M-%S"),
+            recording.wake_word.replace(' ', "_"),
+            recording.recording_id,
+            recording.file_extension()
+        );
         let mut file = File::create(&filename).await?;
-        file.write_all(&msg).await?;
+        file.write_all(&audio_file).await?;
         drop(file);
         println!("Saved file as {}", &filename);
-        counter += 1;
     }
 }
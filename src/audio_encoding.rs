@@ -0,0 +1,539 @@
+//! Encoders for compressed audio formats
+//!
+//! FLAC is encoded using fixed linear predictors and rice coded residuals as described in <https://xiph.org/flac/format.html>
+//! Opus is encoded using libopus and wrapped in an Ogg container as described in <https://datatracker.ietf.org/doc/html/rfc7845>
+
+use anyhow::Context;
+use audiopus::{coder::Encoder as OpusEncoder, Application, Channels, SampleRate};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+/// Format of audio files published and uploaded for transcription
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    /// Uncompressed 16 bit PCM WAV
+    #[default]
+    Wav,
+    /// Lossless FLAC
+    Flac,
+    /// Opus in an Ogg container
+    OggOpus,
+}
+
+impl AudioFormat {
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::OggOpus => "ogg",
+        }
+    }
+}
+
+/// Read mono 16 bit WAV recorded at `sample_rate`
+pub fn read_wav(path: &Path, sample_rate: u32) -> anyhow::Result<Vec<i16>> {
+    let reader =
+        hound::WavReader::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let spec = reader.spec();
+    if spec.channels != 1 || spec.bits_per_sample != 16 || spec.sample_rate != sample_rate {
+        anyhow::bail!(
+            "Audio file {:?} has to be mono 16 bit {}Hz but is {:?}",
+            path,
+            sample_rate,
+            spec
+        );
+    }
+    Ok(reader.into_samples::<i16>().collect::<Result<_, _>>()?)
+}
+
+/// WAV files in directory sorted by name
+pub fn wav_files(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for file in std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {:?}", directory))?
+    {
+        let file = file?.path();
+        if file.extension().and_then(|ext| ext.to_str()) == Some("wav") {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+const FLAC_BLOCK_SIZE: usize = 4096;
+const FLAC_BITS_PER_SAMPLE: u32 = 16;
+const FLAC_PREDICTOR_ORDER: usize = 2;
+const FLAC_MAX_RICE_PARAMETER: u32 = 14;
+
+/// Encode mono 16 bit samples as a FLAC file
+pub fn encode_flac(samples: &[i16], sample_rate: u32) -> anyhow::Result<Vec<u8>> {
+    if sample_rate == 0 || sample_rate >= 1 << 20 {
+        anyhow::bail!("Sample rate {} not supported by FLAC", sample_rate);
+    }
+
+    let mut writer = BitWriter::default();
+    writer.write_bytes(b"fLaC");
+
+    // STREAMINFO metadata block, marked as last metadata block
+    writer.write(1, 1);
+    writer.write(0, 7);
+    writer.write(34, 24);
+    let min_block_size = samples.len().clamp(16, FLAC_BLOCK_SIZE) as u64;
+    writer.write(min_block_size, 16);
+    writer.write(FLAC_BLOCK_SIZE as u64, 16);
+    // minimum and maximum frame size are unknown
+    writer.write(0, 24);
+    writer.write(0, 24);
+    writer.write(sample_rate as u64, 20);
+    // mono
+    writer.write(0, 3);
+    writer.write((FLAC_BITS_PER_SAMPLE - 1) as u64, 5);
+    writer.write(samples.len() as u64, 36);
+    // MD5 signature is optional and left unset
+    writer.write_bytes(&[0; 16]);
+
+    for (frame_number, block) in samples.chunks(FLAC_BLOCK_SIZE).enumerate() {
+        let frame = encode_flac_frame(block, frame_number as u64);
+        writer.write_bytes(&frame);
+    }
+
+    Ok(writer.finish())
+}
+
+fn encode_flac_frame(block: &[i16], frame_number: u64) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+
+    // frame header
+    writer.write(0b11111111111110, 14);
+    // reserved and fixed blocking strategy
+    writer.write(0, 1);
+    writer.write(0, 1);
+    // block size stored as 16 bit value at the end of the header
+    writer.write(0b0111, 4);
+    // sample rate taken from STREAMINFO
+    writer.write(0b0000, 4);
+    // mono
+    writer.write(0b0000, 4);
+    // 16 bits per sample
+    writer.write(0b100, 3);
+    writer.write(0, 1);
+    writer.write_bytes(&utf8_encode_frame_number(frame_number));
+    writer.write((block.len() - 1) as u64, 16);
+    let header_crc = crc8(writer.bytes());
+    writer.write(header_crc as u64, 8);
+
+    // subframe
+    if block.len() <= FLAC_PREDICTOR_ORDER {
+        // verbatim
+        writer.write(0b00000010, 8);
+        for sample in block {
+            writer.write_signed(*sample as i64, FLAC_BITS_PER_SAMPLE);
+        }
+    } else {
+        // fixed predictor
+        writer.write(0b00010000 | (FLAC_PREDICTOR_ORDER as u64) << 1, 8);
+        for sample in &block[..FLAC_PREDICTOR_ORDER] {
+            writer.write_signed(*sample as i64, FLAC_BITS_PER_SAMPLE);
+        }
+        let residuals: Vec<i64> = block
+            .windows(FLAC_PREDICTOR_ORDER + 1)
+            .map(|window| window[2] as i64 - 2 * window[1] as i64 + window[0] as i64)
+            .collect();
+        write_rice_residuals(&mut writer, &residuals);
+    }
+
+    writer.align_to_byte();
+    let frame_crc = crc16(writer.bytes());
+    writer.write(frame_crc as u64, 16);
+    writer.finish()
+}
+
+fn write_rice_residuals(writer: &mut BitWriter, residuals: &[i64]) {
+    let folded: Vec<u64> = residuals
+        .iter()
+        .map(|residual| ((residual << 1) ^ (residual >> 63)) as u64)
+        .collect();
+
+    let rice_parameter = (0..=FLAC_MAX_RICE_PARAMETER)
+        .min_by_key(|parameter| {
+            folded
+                .iter()
+                .map(|value| (value >> parameter) + 1 + *parameter as u64)
+                .sum::<u64>()
+        })
+        .unwrap_or_default();
+
+    // 4 bit rice parameters with a single partition
+    writer.write(0b00, 2);
+    writer.write(0, 4);
+    writer.write(rice_parameter as u64, 4);
+    for value in folded {
+        let quotient = value >> rice_parameter;
+        for _ in 0..quotient {
+            writer.write(0, 1);
+        }
+        writer.write(1, 1);
+        writer.write(value & ((1 << rice_parameter) - 1), rice_parameter);
+    }
+}
+
+/// Frame numbers use the same variable length encoding as UTF-8
+fn utf8_encode_frame_number(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let mut continuation_bytes = vec![];
+    let mut remaining = value;
+    let mut first_byte_capacity = 6;
+    while remaining >= 1 << first_byte_capacity {
+        continuation_bytes.push(0x80 | (remaining & 0x3F) as u8);
+        remaining >>= 6;
+        first_byte_capacity -= 1;
+    }
+    let byte_count = continuation_bytes.len() + 1;
+    let prefix = !(0xFFu8 >> byte_count);
+    let mut encoded = vec![prefix | remaining as u8];
+    encoded.extend(continuation_bytes.iter().rev());
+    encoded
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.accumulator = (self.accumulator << 1) | ((value >> bit) & 1);
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.data.push(self.accumulator as u8);
+                self.accumulator = 0;
+                self.bit_count = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+
+    fn align_to_byte(&mut self) {
+        if self.bit_count > 0 {
+            self.write(0, 8 - self.bit_count);
+        }
+    }
+
+    /// Bytes written so far. Only complete bytes are included
+    fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align_to_byte();
+        self.data
+    }
+}
+
+const OPUS_FRAME_DURATION_MS: usize = 20;
+const OPUS_GRANULE_SAMPLE_RATE: u64 = 48000;
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+const OPUS_OGG_STREAM_SERIAL: u32 = 1;
+const OPUS_VENDOR: &str = "wakeword";
+
+/// Encode mono 16 bit samples as an Ogg Opus file
+pub fn encode_ogg_opus(samples: &[i16], sample_rate: u32) -> anyhow::Result<Vec<u8>> {
+    let opus_sample_rate = match sample_rate {
+        8000 => SampleRate::Hz8000,
+        12000 => SampleRate::Hz12000,
+        16000 => SampleRate::Hz16000,
+        24000 => SampleRate::Hz24000,
+        48000 => SampleRate::Hz48000,
+        _ => anyhow::bail!("Sample rate {} not supported by Opus", sample_rate),
+    };
+    let encoder = OpusEncoder::new(opus_sample_rate, Channels::Mono, Application::Voip)
+        .context("Failed to create Opus encoder")?;
+
+    // granule positions are always expressed at 48kHz
+    let granule_scale = OPUS_GRANULE_SAMPLE_RATE / sample_rate as u64;
+    let lookahead = encoder
+        .lookahead()
+        .context("Failed to read Opus encoder lookahead")? as usize;
+    let pre_skip = lookahead as u64 * granule_scale;
+
+    let mut file = vec![];
+    {
+        let mut writer = PacketWriter::new(Cursor::new(&mut file));
+
+        let mut opus_head = vec![];
+        opus_head.extend_from_slice(b"OpusHead");
+        // version
+        opus_head.push(1);
+        // channel count
+        opus_head.push(1);
+        opus_head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+        opus_head.extend_from_slice(&sample_rate.to_le_bytes());
+        // output gain
+        opus_head.extend_from_slice(&0_i16.to_le_bytes());
+        // channel mapping family
+        opus_head.push(0);
+        writer
+            .write_packet(
+                opus_head.into_boxed_slice(),
+                OPUS_OGG_STREAM_SERIAL,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )
+            .context("Failed to write Opus header")?;
+
+        let mut opus_tags = vec![];
+        opus_tags.extend_from_slice(b"OpusTags");
+        opus_tags.extend_from_slice(&(OPUS_VENDOR.len() as u32).to_le_bytes());
+        opus_tags.extend_from_slice(OPUS_VENDOR.as_bytes());
+        // no user comments
+        opus_tags.extend_from_slice(&0_u32.to_le_bytes());
+        writer
+            .write_packet(
+                opus_tags.into_boxed_slice(),
+                OPUS_OGG_STREAM_SERIAL,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )
+            .context("Failed to write Opus tags")?;
+
+        let frame_size = sample_rate as usize * OPUS_FRAME_DURATION_MS / 1000;
+        let final_granule_position = pre_skip + samples.len() as u64 * granule_scale;
+        // trailing silence flushes the encoder lookahead so that the final granule position
+        // trims only padding and never cuts off the end of the recording
+        let frame_count = (samples.len() + lookahead).div_ceil(frame_size).max(1);
+        let mut output = [0; OPUS_MAX_PACKET_SIZE];
+        let mut frame = vec![0; frame_size];
+
+        for frame_index in 0..frame_count {
+            // frames past the end of the recording are padded with silence
+            frame.fill(0);
+            let start = (frame_index * frame_size).min(samples.len());
+            let end = (start + frame_size).min(samples.len());
+            frame[..end - start].copy_from_slice(&samples[start..end]);
+
+            let packet_size = encoder
+                .encode(&frame, &mut output)
+                .context("Failed to encode Opus frame")?;
+
+            let is_last = frame_index + 1 == frame_count;
+            let (end_info, granule_position) = if is_last {
+                (PacketWriteEndInfo::EndStream, final_granule_position)
+            } else {
+                (
+                    PacketWriteEndInfo::NormalPacket,
+                    ((frame_index + 1) * frame_size) as u64 * granule_scale,
+                )
+            };
+            writer
+                .write_packet(
+                    output[..packet_size].to_vec().into_boxed_slice(),
+                    OPUS_OGG_STREAM_SERIAL,
+                    end_info,
+                    granule_position,
+                )
+                .context("Failed to write Opus packet")?;
+        }
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_test_wav(path: &Path, channels: u16, sample_rate: u32) {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in 0..320 {
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn read_wav_rejects_other_formats() {
+        let directory = std::env::temp_dir().join(format!("wakeword_wav_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let valid = directory.join("b_valid.wav");
+        let wrong_rate = directory.join("a_8khz.wav");
+        let stereo = directory.join("c_stereo.wav");
+        write_test_wav(&valid, 1, 16000);
+        write_test_wav(&wrong_rate, 1, 8000);
+        write_test_wav(&stereo, 2, 16000);
+        std::fs::write(directory.join("notes.txt"), "not audio").unwrap();
+
+        assert_eq!(read_wav(&valid, 16000).unwrap().len(), 320);
+        assert!(read_wav(&wrong_rate, 16000).is_err());
+        assert!(read_wav(&stereo, 16000).is_err());
+        assert!(read_wav(&directory.join("missing.wav"), 16000).is_err());
+
+        assert_eq!(
+            wav_files(&directory).unwrap(),
+            vec![wrong_rate, valid, stereo]
+        );
+        assert!(wav_files(&directory.join("missing")).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn frame_number_encoding() {
+        assert_eq!(utf8_encode_frame_number(0), vec![0x00]);
+        assert_eq!(utf8_encode_frame_number(0x7F), vec![0x7F]);
+        assert_eq!(utf8_encode_frame_number(0x80), vec![0xC2, 0x80]);
+        assert_eq!(utf8_encode_frame_number(0x7FF), vec![0xDF, 0xBF]);
+        assert_eq!(utf8_encode_frame_number(0x800), vec![0xE0, 0xA0, 0x80]);
+    }
+
+    #[test]
+    fn flac_stream_info() {
+        let samples: Vec<i16> = (0..10_000).map(|i| ((i % 100) * 50) as i16).collect();
+        let flac = encode_flac(&samples, 16000).unwrap();
+        assert_eq!(&flac[..4], b"fLaC");
+        // last metadata block flag and STREAMINFO type
+        assert_eq!(flac[4], 0x80);
+        // compressed smaller than raw PCM
+        assert!(flac.len() < samples.len() * 2);
+    }
+
+    #[test]
+    fn flac_rejects_invalid_sample_rate() {
+        assert!(encode_flac(&[0; 10], 0).is_err());
+    }
+
+    fn decode_flac(flac: &[u8]) -> (u32, Vec<i16>) {
+        let mut reader = claxon::FlacReader::new(Cursor::new(flac)).unwrap();
+        let sample_rate = reader.streaminfo().sample_rate;
+        let samples = reader
+            .samples()
+            .map(|sample| sample.unwrap() as i16)
+            .collect();
+        (sample_rate, samples)
+    }
+
+    #[test]
+    fn flac_round_trip() {
+        // spans multiple blocks with a short final block and full scale swings
+        let samples: Vec<i16> = (0..FLAC_BLOCK_SIZE * 2 + 3)
+            .map(|i| match i % 7 {
+                0 => i16::MIN,
+                1 => i16::MAX,
+                _ => (i as i16).wrapping_mul(31),
+            })
+            .collect();
+        assert_eq!(
+            decode_flac(&encode_flac(&samples, 16000).unwrap()),
+            (16000, samples)
+        );
+    }
+
+    #[test]
+    fn flac_round_trip_shorter_than_predictor() {
+        for length in 0..=FLAC_PREDICTOR_ORDER + 1 {
+            let samples: Vec<i16> = (0..length as i16).map(|i| i * 1000 - 1).collect();
+            assert_eq!(
+                decode_flac(&encode_flac(&samples, 8000).unwrap()).1,
+                samples
+            );
+        }
+    }
+
+    /// Page granule position and end of stream flag of each audio packet
+    fn opus_packet_granules(ogg_opus: &[u8]) -> (u64, Vec<(u64, bool)>) {
+        let mut reader = ogg::PacketReader::new(Cursor::new(ogg_opus));
+        let head = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+        let tags = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push((packet.absgp_page(), packet.last_in_stream()));
+        }
+        (pre_skip, packets)
+    }
+
+    #[test]
+    fn opus_flushes_lookahead() {
+        let sample_rate = 16000;
+        let frame_size = sample_rate as usize * OPUS_FRAME_DURATION_MS / 1000;
+        // exactly fills frames so only the lookahead needs an extra frame
+        let samples = vec![1000; frame_size * 3];
+        let (pre_skip, packets) =
+            opus_packet_granules(&encode_ogg_opus(&samples, sample_rate).unwrap());
+
+        assert!(pre_skip > 0);
+        assert_eq!(packets.len(), 4);
+        let (final_granule, last_in_stream) = *packets.last().unwrap();
+        assert!(last_in_stream);
+        assert_eq!(final_granule, pre_skip + samples.len() as u64 * 3);
+        // end trimming must not exceed the decoded samples
+        assert!(final_granule <= packets.len() as u64 * frame_size as u64 * 3);
+    }
+
+    #[test]
+    fn opus_empty_recording() {
+        let (pre_skip, packets) = opus_packet_granules(&encode_ogg_opus(&[], 48000).unwrap());
+        assert_eq!(packets, vec![(pre_skip, true)]);
+    }
+
+    #[test]
+    fn opus_rejects_invalid_sample_rate() {
+        assert!(encode_ogg_opus(&[0; 10], 44100).is_err());
+    }
+}
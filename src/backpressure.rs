@@ -0,0 +1,279 @@
+//! Sending listener output over bounded channels without blocking audio processing
+//!
+//! Every message that doesn't fit into the channel is either counted as dropped
+//! or held back and retried, depending on its delivery policy.
+//! At most channel capacity messages are held back, beyond that the oldest is dropped
+
+use std::{collections::VecDeque, sync::Arc};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tracing::warn;
+
+use crate::metrics::{Metrics, MESSAGES_DELAYED, MESSAGES_DROPPED};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryPolicy {
+    /// Superseded by the next message soon. Dropped when the channel is full
+    Droppable,
+    /// Held back while the channel is full and retried on next send or flush
+    /// Oldest held back message is dropped when too many are waiting
+    Required,
+}
+
+pub trait Delivery {
+    fn delivery_policy(&self) -> DeliveryPolicy;
+    /// Label used when counting dropped and delayed messages
+    fn kind(&self) -> &'static str;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// Waiting for space in the channel
+    HeldBack,
+    Dropped,
+}
+
+pub struct Outbox<T: Delivery> {
+    /// Channel name used in logs and metrics
+    channel: &'static str,
+    sender: Sender<T>,
+    /// Required messages waiting for space in order of sending
+    /// Never longer than channel capacity
+    pending: VecDeque<T>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T: Delivery> Outbox<T> {
+    pub fn new(channel: &'static str, sender: Sender<T>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            channel,
+            sender,
+            pending: VecDeque::new(),
+            metrics,
+        }
+    }
+
+    /// Number of held back messages
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    /// Send held back messages that fit into the channel now
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        while let Some(message) = self.pending.pop_front() {
+            match self.sender.try_send(message) {
+                Ok(()) => (),
+                Err(TrySendError::Full(message)) => {
+                    self.pending.push_front(message);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => anyhow::bail!("{} channel closed", self.channel),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, message: T) -> anyhow::Result<SendOutcome> {
+        self.flush()?;
+        // new messages queue behind held back ones so order is preserved
+        let message = if self.pending.is_empty() {
+            match self.sender.try_send(message) {
+                Ok(()) => return Ok(SendOutcome::Sent),
+                Err(TrySendError::Full(message)) => message,
+                Err(TrySendError::Closed(_)) => anyhow::bail!("{} channel closed", self.channel),
+            }
+        } else {
+            message
+        };
+
+        let labels = [("channel", self.channel), ("kind", message.kind())];
+        match message.delivery_policy() {
+            DeliveryPolicy::Droppable => {
+                self.metrics.increment(&MESSAGES_DROPPED, &labels);
+                Ok(SendOutcome::Dropped)
+            }
+            DeliveryPolicy::Required => {
+                if self.pending.is_empty() {
+                    warn!("{} channel is full. Holding back messages", self.channel);
+                }
+                self.metrics.increment(&MESSAGES_DELAYED, &labels);
+                if self.pending.len() >= self.capacity() {
+                    if let Some(oldest) = self.pending.pop_front() {
+                        self.count_dropped(&oldest);
+                    }
+                }
+                self.pending.push_back(message);
+                Ok(SendOutcome::HeldBack)
+            }
+        }
+    }
+
+    fn count_dropped(&self, message: &T) {
+        self.metrics.increment(
+            &MESSAGES_DROPPED,
+            &[("channel", self.channel), ("kind", message.kind())],
+        );
+    }
+}
+
+impl<T: Delivery> Drop for Outbox<T> {
+    fn drop(&mut self) {
+        // held back messages are lost when the listener is rebuilt
+        if !self.pending.is_empty() {
+            warn!(
+                "Dropping {} held back {} messages",
+                self.pending.len(),
+                self.channel
+            );
+        }
+        for message in &self.pending {
+            self.count_dropped(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum TestMessage {
+        Telemetry(u32),
+        Event(u32),
+    }
+
+    impl Delivery for TestMessage {
+        fn delivery_policy(&self) -> DeliveryPolicy {
+            match self {
+                TestMessage::Telemetry(_) => DeliveryPolicy::Droppable,
+                TestMessage::Event(_) => DeliveryPolicy::Required,
+            }
+        }
+
+        fn kind(&self) -> &'static str {
+            match self {
+                TestMessage::Telemetry(_) => "telemetry",
+                TestMessage::Event(_) => "event",
+            }
+        }
+    }
+
+    fn counter(metrics: &Metrics, name: &str, kind: &str) -> u64 {
+        metrics
+            .snapshot()
+            .counters
+            .iter()
+            .find(|counter| counter.name == name && counter.labels["kind"] == kind)
+            .map(|counter| counter.value)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn drops_only_droppable_messages() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let mut outbox = Outbox::new("test", sender, metrics.clone());
+
+        assert_eq!(
+            outbox.send(TestMessage::Event(1)).unwrap(),
+            SendOutcome::Sent
+        );
+        assert_eq!(
+            outbox.send(TestMessage::Telemetry(1)).unwrap(),
+            SendOutcome::Sent
+        );
+        assert_eq!(
+            outbox.send(TestMessage::Telemetry(2)).unwrap(),
+            SendOutcome::Dropped
+        );
+        assert_eq!(
+            outbox.send(TestMessage::Event(2)).unwrap(),
+            SendOutcome::HeldBack
+        );
+        // freed slot goes to the held back event first
+        assert_eq!(receiver.try_recv().unwrap(), TestMessage::Event(1));
+        assert_eq!(
+            outbox.send(TestMessage::Telemetry(3)).unwrap(),
+            SendOutcome::Dropped
+        );
+        assert_eq!(outbox.pending(), 0);
+
+        assert_eq!(counter(&metrics, MESSAGES_DROPPED.name, "telemetry"), 2);
+        assert_eq!(counter(&metrics, MESSAGES_DELAYED.name, "event"), 1);
+        assert_eq!(receiver.try_recv().unwrap(), TestMessage::Telemetry(1));
+        assert_eq!(receiver.try_recv().unwrap(), TestMessage::Event(2));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn held_back_messages_keep_order() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let mut outbox = Outbox::new("test", sender, metrics);
+
+        for index in 0..4 {
+            outbox.send(TestMessage::Event(index)).unwrap();
+        }
+        assert_eq!(outbox.pending(), 2);
+        assert_eq!(outbox.capacity(), 2);
+
+        let mut received = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            received.push(message);
+            outbox.flush().unwrap();
+        }
+        assert_eq!(outbox.pending(), 0);
+        assert_eq!(received, (0..4).map(TestMessage::Event).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn closed_channel_fails() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut outbox = Outbox::new("test", sender, Arc::new(Metrics::default()));
+        drop(receiver);
+        assert!(outbox.send(TestMessage::Telemetry(1)).is_err());
+    }
+
+    #[test]
+    fn held_back_messages_are_bounded_by_capacity() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let mut outbox = Outbox::new("test", sender, metrics.clone());
+
+        for index in 0..10 {
+            assert_ne!(
+                outbox.send(TestMessage::Event(index)).unwrap(),
+                SendOutcome::Dropped
+            );
+            assert!(outbox.pending() <= outbox.capacity());
+        }
+        assert_eq!(outbox.pending(), 2);
+        // oldest held back messages were dropped, newest are kept
+        assert_eq!(counter(&metrics, MESSAGES_DROPPED.name, "event"), 6);
+        assert_eq!(counter(&metrics, MESSAGES_DELAYED.name, "event"), 8);
+
+        let mut received = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            received.push(message);
+            outbox.flush().unwrap();
+        }
+        assert_eq!(received, [0, 1, 8, 9].map(TestMessage::Event));
+    }
+
+    #[test]
+    fn dropping_outbox_counts_held_back_messages() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+        let mut outbox = Outbox::new("test", sender, metrics.clone());
+        for index in 0..3 {
+            outbox.send(TestMessage::Event(index)).unwrap();
+        }
+        assert_eq!(counter(&metrics, MESSAGES_DROPPED.name, "event"), 1);
+        drop(outbox);
+        assert_eq!(counter(&metrics, MESSAGES_DROPPED.name, "event"), 2);
+    }
+}
@@ -0,0 +1,223 @@
+//! Offline benchmark of the per frame detection path
+
+use anyhow::Context;
+use std::{
+    path::Path,
+    slice::ChunksExact,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio_encoding::read_wav,
+    configuration::PicovoiceConfig,
+    stage_timing::{STAGE_COBRA, STAGE_FRAME, STAGE_PORCUPINE, STAGE_VALIDATION_BUFFER},
+    wakeword_validation::AudioBuffer,
+    WakewordError,
+};
+
+pub struct StageReport {
+    pub stage: &'static str,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl StageReport {
+    fn new(stage: &'static str, mut durations: Vec<Duration>) -> Self {
+        durations.sort();
+        Self {
+            stage,
+            p50: percentile(&durations, 0.5),
+            p90: percentile(&durations, 0.9),
+            p99: percentile(&durations, 0.99),
+            max: durations.last().copied().unwrap_or_default(),
+            total: durations.iter().sum(),
+        }
+    }
+}
+
+pub struct BenchReport {
+    pub frames: usize,
+    /// Duration of audio in one frame
+    pub frame_budget: Duration,
+    pub audio_duration: Duration,
+    pub stages: Vec<StageReport>,
+}
+
+impl BenchReport {
+    /// Processing time divided by audio duration. Has to stay well below 1
+    pub fn real_time_factor(&self) -> f32 {
+        let processing: Duration = self
+            .stages
+            .iter()
+            .filter(|stage| stage.stage == STAGE_FRAME)
+            .map(|stage| stage.total)
+            .sum();
+        processing.as_secs_f32() / self.audio_duration.as_secs_f32()
+    }
+}
+
+/// Nearest rank percentile of sorted durations
+pub fn percentile(sorted: &[Duration], percentile: f32) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percentile * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Deterministic quiet white noise
+pub fn synthetic_audio(duration: Duration, sample_rate: u32) -> Vec<i16> {
+    let samples = (duration.as_secs_f64() * sample_rate as f64) as usize;
+    // xorshift is good enough for noise and avoids pulling in rand
+    let mut state: u32 = 0x2545_f491;
+    (0..samples)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 16) as i16 / 16
+        })
+        .collect()
+}
+
+/// Frames of `frame_length` samples. Fails if there isn't a single whole frame
+fn frames(samples: &[i16], frame_length: usize) -> anyhow::Result<ChunksExact<'_, i16>> {
+    if frame_length == 0 || samples.len() < frame_length {
+        anyhow::bail!(
+            "Audio of {} samples is shorter than one frame of {} samples",
+            samples.len(),
+            frame_length
+        );
+    }
+    Ok(samples.chunks_exact(frame_length))
+}
+
+/// Run porcupine, cobra and the validation buffer over audio frame by frame
+///
+/// Synthetic audio of `duration` is used if no WAV is provided
+pub fn run_bench(
+    config: &PicovoiceConfig,
+    wav: Option<&Path>,
+    duration: Duration,
+) -> anyhow::Result<BenchReport> {
+    let porcupine = config.build_porcupine()?;
+    let cobra = config.build_cobra()?;
+    let sample_rate = porcupine.sample_rate();
+    let frame_length = porcupine.frame_length() as usize;
+
+    let samples = match wav {
+        Some(wav) => read_wav(wav, sample_rate)?,
+        None => synthetic_audio(duration, sample_rate),
+    };
+    let frame_budget = Duration::from_secs_f64(frame_length as f64 / sample_rate as f64);
+
+    let mut validation_buffer = AudioBuffer::default();
+    let mut validation_buffer_timings = vec![];
+    let mut porcupine_timings = vec![];
+    let mut cobra_timings = vec![];
+    let mut frame_timings = vec![];
+
+    // buffer retention depends on time so pretend audio arrives in real time
+    let start = Instant::now();
+    for (index, frame) in frames(&samples, frame_length)?.enumerate() {
+        let frame_started = Instant::now();
+
+        let started = Instant::now();
+        validation_buffer.insert(start + frame_budget * index as u32, frame);
+        validation_buffer_timings.push(started.elapsed());
+
+        let started = Instant::now();
+        porcupine
+            .process(frame)
+            .context("Failed to process audio frame")?;
+        porcupine_timings.push(started.elapsed());
+
+        let started = Instant::now();
+        cobra
+            .process(frame)
+            .map_err(WakewordError::CobraError)
+            .context("Cobra processing failed")?;
+        cobra_timings.push(started.elapsed());
+
+        frame_timings.push(frame_started.elapsed());
+    }
+
+    let frames = frame_timings.len();
+    Ok(BenchReport {
+        frames,
+        frame_budget,
+        audio_duration: frame_budget * frames as u32,
+        stages: vec![
+            StageReport::new(STAGE_VALIDATION_BUFFER, validation_buffer_timings),
+            StageReport::new(STAGE_PORCUPINE, porcupine_timings),
+            StageReport::new(STAGE_COBRA, cobra_timings),
+            StageReport::new(STAGE_FRAME, frame_timings),
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentile() {
+        let durations: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&durations, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&durations, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&durations, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&durations, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn synthetic_noise() {
+        let samples = synthetic_audio(Duration::from_secs(2), 16000);
+        assert_eq!(samples.len(), 32000);
+        assert_eq!(samples, synthetic_audio(Duration::from_secs(2), 16000));
+        assert!(samples.iter().any(|sample| *sample != 0));
+        assert!(samples
+            .iter()
+            .all(|sample| sample.abs() <= i16::MAX / 16 + 1));
+    }
+
+    #[test]
+    fn audio_shorter_than_frame_fails() {
+        let samples = synthetic_audio(Duration::from_millis(20), 16000);
+        assert!(frames(&samples, 512).is_err());
+        assert!(frames(&[], 512).is_err());
+        assert!(frames(&samples, 0).is_err());
+        // incomplete last frame is left out
+        assert_eq!(frames(&samples, 100).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn empty_stage_report() {
+        let report = StageReport::new(STAGE_COBRA, vec![]);
+        assert_eq!(report.p99, Duration::ZERO);
+        assert_eq!(report.max, Duration::ZERO);
+        assert_eq!(report.total, Duration::ZERO);
+    }
+
+    #[test]
+    fn report_real_time_factor() {
+        let report = BenchReport {
+            frames: 2,
+            frame_budget: Duration::from_millis(32),
+            audio_duration: Duration::from_millis(64),
+            stages: vec![
+                StageReport::new(STAGE_PORCUPINE, vec![Duration::from_millis(4); 2]),
+                StageReport::new(
+                    STAGE_FRAME,
+                    vec![Duration::from_millis(10), Duration::from_millis(6)],
+                ),
+            ],
+        };
+        assert!((report.real_time_factor() - 0.25).abs() < 1e-6);
+        assert_eq!(report.stages[1].max, Duration::from_millis(10));
+        assert_eq!(report.stages[1].p50, Duration::from_millis(6));
+    }
+}
@@ -0,0 +1,339 @@
+//! Sensitivity sweep over labelled recordings
+//!
+//! Calibration directory contains `<keyword>/*.wav` with recordings of each keyword
+//! named as in the config ignoring case
+//! and `negative/*.wav` with background audio that doesn't contain any keyword
+
+use anyhow::Context;
+use porcupine::Porcupine;
+use serde_yaml::{Mapping, Value};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{
+    audio_encoding::{read_wav, wav_files},
+    configuration::PicovoiceConfig,
+};
+
+const NEGATIVE_DIRECTORY: &str = "negative";
+
+pub struct CalibrationPoint {
+    pub sensitivity: f32,
+    /// Fraction of positive recordings with a detection
+    pub detection_rate: f32,
+    pub false_alarms_per_hour: f32,
+}
+
+pub struct KeywordCalibration {
+    pub keyword: String,
+    pub points: Vec<CalibrationPoint>,
+    /// Best detection rate within false alarm budget
+    pub recommended: Option<f32>,
+}
+
+/// Evenly spaced sensitivities between 0 and 1 exclusive
+pub fn sensitivity_sweep(steps: usize) -> Vec<f32> {
+    (1..steps).map(|step| step as f32 / steps as f32).collect()
+}
+
+/// Highest detection rate with false alarms within budget
+///
+/// Ties prefer fewer false alarms and then lower sensitivity
+pub fn recommend(points: &[CalibrationPoint], max_false_alarms_per_hour: f32) -> Option<f32> {
+    points
+        .iter()
+        .filter(|point| point.false_alarms_per_hour <= max_false_alarms_per_hour)
+        .max_by(|a, b| {
+            a.detection_rate
+                .total_cmp(&b.detection_rate)
+                .then(b.false_alarms_per_hour.total_cmp(&a.false_alarms_per_hour))
+                .then(b.sensitivity.total_cmp(&a.sensitivity))
+        })
+        .map(|point| point.sensitivity)
+}
+
+fn count_detections(porcupine: &Porcupine, samples: &[i16]) -> anyhow::Result<usize> {
+    let mut detections = 0;
+    for frame in samples.chunks_exact(porcupine.frame_length() as usize) {
+        let keyword_index = porcupine
+            .process(frame)
+            .context("Failed to process audio frame")?;
+        if keyword_index >= 0 {
+            detections += 1;
+        }
+    }
+    Ok(detections)
+}
+
+/// Keyword names are lowercased when loading the config so directories are matched ignoring case
+fn keyword_directory(directory: &Path, keyword: &str) -> anyhow::Result<Option<PathBuf>> {
+    let keyword = keyword.to_lowercase();
+    for entry in std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {:?}", directory))?
+    {
+        let path = entry?.path();
+        let matches = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.to_lowercase() == keyword);
+        if matches && path.is_dir() {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+fn read_recordings(directory: &Path, sample_rate: u32) -> anyhow::Result<Vec<Vec<i16>>> {
+    wav_files(directory)?
+        .iter()
+        .map(|file| read_wav(file, sample_rate))
+        .collect()
+}
+
+/// Run every keyword with positive recordings at each sensitivity of the sweep
+pub fn calibrate(
+    config: &PicovoiceConfig,
+    directory: &Path,
+    sensitivities: &[f32],
+    max_false_alarms_per_hour: f32,
+) -> anyhow::Result<Vec<KeywordCalibration>> {
+    // all keywords share the sample rate of the porcupine model
+    let sample_rate = config.build_porcupine()?.sample_rate();
+    let negative_recordings = read_recordings(&directory.join(NEGATIVE_DIRECTORY), sample_rate)?;
+    let negative_samples: usize = negative_recordings.iter().map(Vec::len).sum();
+    let negative_duration = Duration::from_secs_f64(negative_samples as f64 / sample_rate as f64);
+    if negative_duration.is_zero() {
+        anyhow::bail!("Negative recordings are required to measure false alarms");
+    }
+    info!(
+        "Loaded {:.0}s of negative audio",
+        negative_duration.as_secs_f32()
+    );
+
+    let mut calibrations = vec![];
+    for (keyword, keyword_path) in config.keyword_pairs()? {
+        let Some(positive_directory) = keyword_directory(directory, &keyword)? else {
+            warn!("No recordings for {:?} in {:?}", keyword, directory);
+            continue;
+        };
+        let positive_recordings = read_recordings(&positive_directory, sample_rate)?;
+        let mut points = vec![];
+        for &sensitivity in sensitivities {
+            let porcupine = config.build_single_keyword_porcupine(&keyword_path, sensitivity)?;
+            let mut detected = 0;
+            for recording in &positive_recordings {
+                if count_detections(&porcupine, recording)? > 0 {
+                    detected += 1;
+                }
+            }
+            let mut false_alarms = 0;
+            for recording in &negative_recordings {
+                false_alarms += count_detections(&porcupine, recording)?;
+            }
+
+            let point = CalibrationPoint {
+                sensitivity,
+                detection_rate: detected as f32 / positive_recordings.len().max(1) as f32,
+                false_alarms_per_hour: false_alarms as f32 / negative_duration.as_secs_f32()
+                    * 3600.0,
+            };
+            info!(
+                "{:?} sensitivity {:.2}: detection rate {:.1}%, {:.2} false alarms per hour",
+                keyword,
+                point.sensitivity,
+                point.detection_rate * 100.0,
+                point.false_alarms_per_hour
+            );
+            points.push(point);
+        }
+        let recommended = recommend(&points, max_false_alarms_per_hour);
+        calibrations.push(KeywordCalibration {
+            keyword,
+            points,
+            recommended,
+        });
+    }
+    Ok(calibrations)
+}
+
+/// Set `picovoice.keyword_settings.<keyword>.sensitivity` in YAML config
+///
+/// Existing keyword entries are updated ignoring case because runtime keywords are lowercased.
+/// Comments and formatting of the original file are not preserved
+pub fn write_sensitivities(
+    config: &str,
+    sensitivities: &[(String, f32)],
+) -> anyhow::Result<String> {
+    let mut config: Value = serde_yaml::from_str(config).context("Failed to parse config")?;
+    let root = config
+        .as_mapping_mut()
+        .context("Config root is not a mapping")?;
+    let keyword_settings = child_mapping(child_mapping(root, "picovoice")?, "keyword_settings")?;
+    for (keyword, sensitivity) in sensitivities {
+        // avoid writing f32 rounding errors into config
+        let sensitivity = (*sensitivity as f64 * 100.0).round() / 100.0;
+        let key = keyword_settings
+            .keys()
+            .filter_map(Value::as_str)
+            .find(|key| key.to_lowercase() == keyword.to_lowercase())
+            .unwrap_or(keyword)
+            .to_owned();
+        child_mapping(keyword_settings, &key)?
+            .insert(Value::from("sensitivity"), Value::from(sensitivity));
+    }
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+fn child_mapping<'a>(mapping: &'a mut Mapping, key: &str) -> anyhow::Result<&'a mut Mapping> {
+    let key = Value::from(key);
+    match mapping.get(&key) {
+        Some(Value::Mapping(_)) => (),
+        None | Some(Value::Null) => {
+            mapping.insert(key.clone(), Value::Mapping(Mapping::new()));
+        }
+        Some(_) => anyhow::bail!("Config key {:?} is not a mapping", key),
+    }
+    mapping
+        .get_mut(&key)
+        .and_then(Value::as_mapping_mut)
+        .context("Config key is not a mapping")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(values: &[(f32, f32, f32)]) -> Vec<CalibrationPoint> {
+        values
+            .iter()
+            .map(
+                |&(sensitivity, detection_rate, false_alarms_per_hour)| CalibrationPoint {
+                    sensitivity,
+                    detection_rate,
+                    false_alarms_per_hour,
+                },
+            )
+            .collect()
+    }
+
+    #[test]
+    fn sweep_excludes_bounds() {
+        assert_eq!(sensitivity_sweep(4), vec![0.25, 0.5, 0.75]);
+        assert!(sensitivity_sweep(1).is_empty());
+        assert!(sensitivity_sweep(0).is_empty());
+    }
+
+    #[test]
+    fn recommendation_within_false_alarm_budget() {
+        let points = points(&[
+            (0.2, 0.7, 0.0),
+            (0.4, 0.9, 0.0),
+            (0.5, 0.9, 0.5),
+            (0.6, 0.95, 2.0),
+        ]);
+        assert_eq!(recommend(&points, 1.0), Some(0.4));
+        assert_eq!(recommend(&points, 5.0), Some(0.6));
+        // budget is inclusive
+        assert_eq!(recommend(&points, 2.0), Some(0.6));
+    }
+
+    #[test]
+    fn nothing_recommended_outside_budget() {
+        assert_eq!(recommend(&[], 1.0), None);
+        assert_eq!(recommend(&points(&[(0.5, 1.0, 3.0)]), 1.0), None);
+    }
+
+    #[test]
+    fn ties_prefer_lower_sensitivity() {
+        let points = points(&[(0.7, 0.0, 0.0), (0.3, 0.0, 0.0), (0.5, 0.0, 0.0)]);
+        assert_eq!(recommend(&points, 0.0), Some(0.3));
+    }
+
+    #[test]
+    fn write_back() {
+        let config = r#"
+picovoice:
+  access_key: "ACCESS_KEY"
+  keyword_settings:
+    "Hey Hopper":
+      follow_up_window_ms: 5000
+    "Songbird":
+"#;
+        let config = write_sensitivities(
+            config,
+            // runtime keywords as lowercased by the config loader
+            &[
+                (String::from("hey hopper"), 0.3),
+                (String::from("songbird"), 0.45),
+                (String::from("wintermute"), 0.700_000_1),
+            ],
+        )
+        .unwrap();
+        let config: Value = serde_yaml::from_str(&config).unwrap();
+        let keyword_settings = &config["picovoice"]["keyword_settings"];
+        // existing entries are updated instead of adding lowercased duplicates
+        assert_eq!(keyword_settings.as_mapping().unwrap().len(), 3);
+        assert_eq!(
+            keyword_settings["Hey Hopper"]["sensitivity"],
+            Value::from(0.3)
+        );
+        assert_eq!(
+            keyword_settings["Hey Hopper"]["follow_up_window_ms"],
+            Value::from(5000)
+        );
+        assert_eq!(
+            keyword_settings["Songbird"]["sensitivity"],
+            Value::from(0.45)
+        );
+        // rounded to two decimals
+        assert_eq!(
+            keyword_settings["wintermute"]["sensitivity"],
+            Value::from(0.7)
+        );
+        assert_eq!(config["picovoice"]["access_key"], Value::from("ACCESS_KEY"));
+    }
+
+    #[test]
+    fn keyword_directory_ignores_case() {
+        let directory =
+            std::env::temp_dir().join(format!("wakeword_calibration_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(directory.join("Hey Hopper")).unwrap();
+        std::fs::write(directory.join("songbird"), b"").unwrap();
+
+        assert_eq!(
+            keyword_directory(&directory, "hey hopper").unwrap(),
+            Some(directory.join("Hey Hopper"))
+        );
+        // files aren't recording directories
+        assert_eq!(keyword_directory(&directory, "songbird").unwrap(), None);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn write_back_creates_missing_sections() {
+        let config = write_sensitivities("app: {}\n", &[(String::from("Songbird"), 0.5)]).unwrap();
+        let config: Value = serde_yaml::from_str(&config).unwrap();
+        assert_eq!(
+            config["picovoice"]["keyword_settings"]["Songbird"]["sensitivity"],
+            Value::from(0.5)
+        );
+        assert_eq!(config["app"], Value::Mapping(Mapping::new()));
+    }
+
+    #[test]
+    fn write_back_rejects_unexpected_config_shape() {
+        let sensitivities = [(String::from("Songbird"), 0.5)];
+        assert!(write_sensitivities("picovoice: [", &sensitivities).is_err());
+        assert!(write_sensitivities("- picovoice", &sensitivities).is_err());
+        assert!(write_sensitivities("picovoice: 5", &sensitivities).is_err());
+        assert!(write_sensitivities(
+            "picovoice:\n  keyword_settings:\n    Songbird: 0.5\n",
+            &sensitivities
+        )
+        .is_err());
+    }
+}
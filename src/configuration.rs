@@ -1,16 +1,29 @@
 use anyhow::Context;
+use cobra::Cobra;
 use config::Config;
 use porcupine::{util::pv_keyword_paths, BuiltinKeywords, Porcupine, PorcupineBuilder};
 use serde::Deserialize;
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     path::PathBuf,
     str::{self, FromStr},
+    time::Duration,
 };
 use tracing::*;
+use uuid::Uuid;
 use zenoh::config::Config as ZenohConfig;
 
-use crate::WakewordError;
+use crate::{
+    audio_encoding::AudioFormat, WakewordError, VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE,
+};
+
+/// Language setting that lets Whisper detect the language
+const AUTO_LANGUAGE: &str = "auto";
+/// Porcupine sensitivity used when none is configured
+pub const DEFAULT_SENSITIVITY: f32 = 0.5;
+/// Speaking sensitivities default to normal sensitivities scaled by this
+const DEFAULT_SPEAKING_SENSITIVITY_SCALE: f32 = 0.5;
 
 /// Use default config if no path is provided
 pub fn get_configuration(config: &Option<PathBuf>) -> anyhow::Result<WakewordConfig> {
@@ -43,15 +56,124 @@ pub struct WakewordConfig {
     pub openai: WakeWordOpenaiConfig,
     #[serde(default)]
     pub zenoh: WakewordZenohConfig,
+    #[serde(default)]
+    pub transcription: TranscriptionConfig,
+    #[serde(default)]
+    pub transcript_filter: TranscriptFilterConfig,
+    /// Ordered rules. First match wins, unmatched transcripts go to the transcript topic
+    #[serde(default)]
+    pub transcript_routes: Vec<TranscriptRouteConfig>,
+    #[serde(default)]
+    pub intents: IntentConfig,
+    #[serde(default)]
+    pub system_prompt: SystemPromptConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+impl WakewordConfig {
+    /// Dismiss phrases including dismiss keywords
+    ///
+    /// Dismiss keywords also dismiss transcripts that contain them
+    pub fn dismiss_phrases(&self) -> Vec<String> {
+        let mut phrases = self.app.dismiss_phrases.clone();
+        for keyword in self.picovoice.all_dismiss_keywords() {
+            if !phrases
+                .iter()
+                .any(|phrase| phrase.trim().eq_ignore_ascii_case(keyword.trim()))
+            {
+                phrases.push(keyword);
+            }
+        }
+        phrases
+    }
+
+    /// Fail early on route and intent wake words that can never be detected
+    ///
+    /// Keyword names are lowercased when loading the config so wake words are compared ignoring case
+    pub fn validate_wake_word_filters(&self) -> anyhow::Result<()> {
+        let keywords: Vec<String> = self
+            .picovoice
+            .keyword_pairs()?
+            .into_iter()
+            .map(|(keyword, _)| keyword.to_lowercase())
+            .collect();
+        for route in &self.transcript_routes {
+            for wake_word in &route.wake_words {
+                if !keywords.contains(&wake_word.to_lowercase()) {
+                    anyhow::bail!(
+                        "Route {:?} wake word {:?} is not a configured keyword",
+                        route.name,
+                        wake_word
+                    );
+                }
+            }
+        }
+        for intent in &self.intents.intents {
+            for wake_word in &intent.wake_words {
+                if !keywords.contains(&wake_word.to_lowercase()) {
+                    anyhow::bail!(
+                        "Intent {:?} wake word {:?} is not a configured keyword",
+                        intent.name,
+                        wake_word
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct AppConfig {
     pub zenoh_prefix: String,
+    /// Prompt templates per wake word. See `SystemPromptConfig` for placeholders
     #[serde(default)]
     pub system_prompts: HashMap<String, String>,
     #[serde(default)]
     pub enable_respeaker_integration: bool,
+    /// Format of recordings published with metadata
+    #[serde(default)]
+    pub recording_audio_format: AudioFormat,
+    /// Format of recordings uploaded for transcription
+    #[serde(default)]
+    pub transcription_audio_format: AudioFormat,
+    /// Publish uncompressed WAV recordings on the wav topic
+    #[serde(default = "default_true")]
+    pub publish_wav_recording: bool,
+    /// Also publish WAV recordings on the exact wav topic without recording ID
+    /// for subscribers that don't use a wildcard yet
+    #[serde(default = "default_true")]
+    pub publish_legacy_wav_topic: bool,
+    /// Phrases that dismiss a transcript. Matched on word boundaries ignoring case
+    /// Dismiss keywords are always included
+    #[serde(default)]
+    pub dismiss_phrases: Vec<String>,
+    /// Full topic on which the TTS node publishes whether the robot is speaking
+    pub speaking_status_topic: Option<String>,
+    /// Robot is considered silent if speaking status isn't refreshed within this time
+    #[serde(default = "default_speaking_status_timeout_ms")]
+    pub speaking_status_timeout_ms: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_speaking_status_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_validation_similarity_threshold() -> f32 {
+    0.75
+}
+
+fn default_validation_deadline_ms() -> u64 {
+    5000
+}
+
+fn default_accepted_sample_rate() -> f32 {
+    0.05
 }
 
 // zenoh topic
@@ -61,8 +183,20 @@ const WAKE_WORD_DETECTION_TOPIC: &str = "event/wake_word_detection";
 const WAKE_WORD_RECORDING_STARTED_TOPIC: &str = "event/recording_started";
 const WAKE_WORD_RECORDING_END_TOPIC: &str = "event/wake_word_detection_end";
 const WAKE_WORD_RECORDING_AUDIO_WAV_FILE: &str = "event/wake_word_audio_wav";
+const WAKE_WORD_RECORDING_AUDIO_TOPIC: &str = "event/wake_word_audio";
 const TRANSCRIPT_TOPIC: &str = "event/transcript";
+const TRANSCRIPTION_FAILED_TOPIC: &str = "event/transcription_failed";
+const TRANSCRIPTION_QUEUE_SATURATED_TOPIC: &str = "event/transcription_queue_saturated";
+const TRANSCRIPT_FILTERED_TOPIC: &str = "event/transcript_filtered";
+const TRANSCRIPT_DISMISSED_TOPIC: &str = "event/transcript_dismissed";
 const PRIVACY_MODE_TOPIC: &str = "control/privacy_mode";
+const EXPECT_FOLLOW_UP_TOPIC: &str = "control/expect_follow_up";
+const STOP_TOPIC: &str = "control/stop";
+const INTENT_TOPIC: &str = "event/intent";
+const PROMPT_CONTEXT_TOPIC: &str = "control/prompt_context";
+const VALIDATION_RESULT_TOPIC: &str = "event/validation_result";
+const STATUS_TOPIC: &str = "status";
+const METRICS_TOPIC: &str = "telemetry/metrics";
 
 impl AppConfig {
     pub fn get_voice_probability_topic(&self) -> String {
@@ -98,13 +232,79 @@ impl AppConfig {
         )
     }
 
+    pub fn get_wake_word_audio_recording_wav_topic_for_recording(
+        &self,
+        recording_id: &Uuid,
+    ) -> String {
+        format!(
+            "{}/{}",
+            self.get_wake_word_audio_recording_wav_topic(),
+            recording_id
+        )
+    }
+
+    pub fn get_wake_word_audio_recording_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, WAKE_WORD_RECORDING_AUDIO_TOPIC)
+    }
+
     pub fn get_transcript_topic(&self) -> String {
         format!("{}/{}", self.zenoh_prefix, TRANSCRIPT_TOPIC)
     }
 
+    pub fn get_transcription_failed_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, TRANSCRIPTION_FAILED_TOPIC)
+    }
+
+    pub fn get_transcription_queue_saturated_topic(&self) -> String {
+        format!(
+            "{}/{}",
+            self.zenoh_prefix, TRANSCRIPTION_QUEUE_SATURATED_TOPIC
+        )
+    }
+
+    pub fn get_transcript_filtered_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, TRANSCRIPT_FILTERED_TOPIC)
+    }
+
+    pub fn speaking_status_timeout(&self) -> Duration {
+        Duration::from_millis(self.speaking_status_timeout_ms)
+    }
+
+    pub fn get_transcript_dismissed_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, TRANSCRIPT_DISMISSED_TOPIC)
+    }
+
     pub fn get_privacy_mode_topic(&self) -> String {
         format!("{}/{}", self.zenoh_prefix, PRIVACY_MODE_TOPIC)
     }
+
+    pub fn get_expect_follow_up_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, EXPECT_FOLLOW_UP_TOPIC)
+    }
+
+    pub fn get_stop_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, STOP_TOPIC)
+    }
+
+    pub fn get_intent_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, INTENT_TOPIC)
+    }
+
+    pub fn get_prompt_context_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, PROMPT_CONTEXT_TOPIC)
+    }
+
+    pub fn get_validation_result_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, VALIDATION_RESULT_TOPIC)
+    }
+
+    pub fn get_status_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, STATUS_TOPIC)
+    }
+
+    pub fn get_metrics_topic(&self) -> String {
+        format!("{}/{}", self.zenoh_prefix, METRICS_TOPIC)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -116,7 +316,41 @@ pub struct PicovoiceConfig {
     pub sensitivities: Option<Vec<f32>>,
     pub audio_device_index: Option<i32>,
     /// Keyword used to dismiss active recording
+    /// Kept for compatibility. Prefer `dismiss_keywords`
     pub dismiss_keyword: Option<String>,
+    /// Keywords used to dismiss active recording
+    #[serde(default)]
+    pub dismiss_keywords: Vec<String>,
+    /// Settings for individual keywords
+    #[serde(default)]
+    pub keyword_settings: HashMap<String, KeywordSettings>,
+    /// How wake words are handled while the robot is speaking
+    #[serde(default)]
+    pub speaking_policy: SpeakingPolicy,
+    /// Sensitivities used while speaking with the reduced sensitivity policy
+    /// Each has to be at most the normal sensitivity of the keyword
+    /// Defaults to half of the normal sensitivities
+    pub speaking_sensitivities: Option<Vec<f32>>,
+    /// Minimum similarity between 0 and 1 of a keyword spelling and the transcript
+    #[serde(default = "default_validation_similarity_threshold")]
+    pub validation_similarity_threshold: f32,
+    /// Second stage verifier used for wake word validation
+    #[serde(default)]
+    pub validation_backend: ValidationBackendConfig,
+    /// What happens when validation fails or misses its deadline
+    #[serde(default)]
+    pub validation_failure_policy: ValidationFailurePolicy,
+    /// Recording is finished without waiting for validation after this deadline
+    #[serde(default = "default_validation_deadline_ms")]
+    pub validation_deadline_ms: u64,
+    /// Save audio of rejected and dismissed detections as training data
+    pub dataset_capture: Option<DatasetCaptureConfig>,
+    /// Collect per stage frame processing histograms for the status queryable
+    #[serde(default)]
+    pub stage_timings: bool,
+    /// Rate and aggregation of published voice probability
+    #[serde(default)]
+    pub voice_probability_telemetry: VoiceProbabilityTelemetryConfig,
     // these are stupid. Why are they not included in a more sensible way?
     pub cobra_lib_path: Option<std::path::PathBuf>,
     pub porcupine_lib_path: Option<std::path::PathBuf>,
@@ -143,6 +377,33 @@ impl PicovoiceConfig {
         }
     }
 
+    pub fn all_dismiss_keywords(&self) -> Vec<String> {
+        self.dismiss_keyword
+            .iter()
+            .chain(self.dismiss_keywords.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Fail early on command keywords that can't publish anything
+    pub fn validate_keyword_settings(&self) -> anyhow::Result<()> {
+        for (keyword, settings) in &self.keyword_settings {
+            if settings.role == KeywordRole::Command {
+                let command = settings
+                    .command
+                    .as_ref()
+                    .with_context(|| format!("Command keyword {:?} has no command", keyword))?;
+                command
+                    .render_payload(keyword, chrono::Utc::now(), None)
+                    .with_context(|| format!("Invalid command payload for {:?}", keyword))?;
+            }
+        }
+        if self.speaking_policy == SpeakingPolicy::ReducedSensitivity {
+            self.speaking_sensitivities(&self.keyword_pairs()?)?;
+        }
+        Ok(())
+    }
+
     pub fn keyword_pairs(&self) -> anyhow::Result<Vec<(String, PathBuf)>> {
         let mut selected_keywords = vec![];
 
@@ -175,16 +436,166 @@ impl PicovoiceConfig {
         Ok(selected_keywords)
     }
 
+    /// Keywords that start recordings with second stage validation
+    pub fn validated_keywords(&self) -> anyhow::Result<Vec<String>> {
+        if self.validation_failure_policy == ValidationFailurePolicy::Skip {
+            return Ok(vec![]);
+        }
+        let dismiss_keywords = self.all_dismiss_keywords();
+        Ok(self
+            .keyword_pairs()?
+            .into_iter()
+            .map(|(keyword, _)| keyword)
+            .filter(|keyword| !dismiss_keywords.contains(keyword))
+            .filter(|keyword| {
+                self.keyword_settings.get(keyword).map_or(true, |settings| {
+                    settings.validate && settings.role == KeywordRole::WakeWord
+                })
+            })
+            .collect())
+    }
+
+    pub fn validation_deadline(&self) -> Duration {
+        Duration::from_millis(self.validation_deadline_ms)
+    }
+
     pub fn build_porcupine(&self) -> anyhow::Result<Porcupine> {
+        let sensitivities = self.detection_sensitivities(&self.keyword_pairs()?);
+        self.build_porcupine_with_sensitivities(sensitivities.as_ref())
+    }
+
+    /// Sensitivities in the order of `keywords`
+    ///
+    /// Per keyword `sensitivity` overrides the value at the same position in `sensitivities`
+    pub fn detection_sensitivities(&self, keywords: &[(String, PathBuf)]) -> Option<Vec<f32>> {
+        let keyword_sensitivity = |keyword: &String| {
+            self.keyword_settings
+                .get(keyword)
+                .and_then(|settings| settings.sensitivity)
+        };
+        if !keywords
+            .iter()
+            .any(|(keyword, _)| keyword_sensitivity(keyword).is_some())
+        {
+            return self.sensitivities.clone();
+        }
+        let sensitivities = keywords
+            .iter()
+            .enumerate()
+            .map(|(index, (keyword, _))| {
+                keyword_sensitivity(keyword)
+                    .or_else(|| {
+                        self.sensitivities
+                            .as_ref()
+                            .and_then(|sensitivities| sensitivities.get(index).copied())
+                    })
+                    .unwrap_or(DEFAULT_SENSITIVITY)
+            })
+            .collect();
+        Some(sensitivities)
+    }
+
+    /// Sensitivities in the order of `keywords` used while the robot is speaking
+    ///
+    /// Fails if any is more sensitive than normal detection
+    pub fn speaking_sensitivities(
+        &self,
+        keywords: &[(String, PathBuf)],
+    ) -> anyhow::Result<Vec<f32>> {
+        let detection_sensitivities = self.detection_sensitivities(keywords).unwrap_or_default();
+        let normal_sensitivities: Vec<f32> = (0..keywords.len())
+            .map(|index| {
+                detection_sensitivities
+                    .get(index)
+                    .copied()
+                    .unwrap_or(DEFAULT_SENSITIVITY)
+            })
+            .collect();
+        let Some(speaking_sensitivities) = &self.speaking_sensitivities else {
+            return Ok(normal_sensitivities
+                .iter()
+                .map(|sensitivity| sensitivity * DEFAULT_SPEAKING_SENSITIVITY_SCALE)
+                .collect());
+        };
+        if speaking_sensitivities.len() != keywords.len() {
+            anyhow::bail!(
+                "Expected {} speaking sensitivities but got {}",
+                keywords.len(),
+                speaking_sensitivities.len()
+            );
+        }
+        for (((keyword, _), speaking), normal) in keywords
+            .iter()
+            .zip(speaking_sensitivities)
+            .zip(&normal_sensitivities)
+        {
+            if speaking > normal {
+                anyhow::bail!(
+                    "Speaking sensitivity {} of {:?} is higher than its sensitivity {}",
+                    speaking,
+                    keyword,
+                    normal
+                );
+            }
+        }
+        Ok(speaking_sensitivities.clone())
+    }
+
+    /// Porcupine used while the robot is speaking
+    /// Only built if reduced sensitivity policy is selected
+    pub fn build_speaking_porcupine(&self) -> anyhow::Result<Option<Porcupine>> {
+        if self.speaking_policy == SpeakingPolicy::ReducedSensitivity {
+            info!("Configuring porcupine used while speaking");
+            let sensitivities = self.speaking_sensitivities(&self.keyword_pairs()?)?;
+            let porcupine = self.build_porcupine_with_sensitivities(Some(&sensitivities))?;
+            Ok(Some(porcupine))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn build_cobra(&self) -> anyhow::Result<Cobra> {
+        let cobra = if let Some(cobra_lib_path) = &self.cobra_lib_path {
+            Cobra::new_with_library(self.access_key.clone(), cobra_lib_path.clone())
+                .map_err(WakewordError::CobraError)
+                .context("Failed to create Cobra")?
+        } else {
+            Cobra::new(self.access_key.clone())
+                .map_err(WakewordError::CobraError)
+                .context("Failed to create Cobra")?
+        };
+        Ok(cobra)
+    }
+
+    /// Porcupine detecting only one keyword used for calibration
+    pub fn build_single_keyword_porcupine(
+        &self,
+        keyword_path: &PathBuf,
+        sensitivity: f32,
+    ) -> anyhow::Result<Porcupine> {
+        self.build_porcupine_from_paths(&[keyword_path], Some(&vec![sensitivity]))
+    }
+
+    fn build_porcupine_with_sensitivities(
+        &self,
+        sensitivities: Option<&Vec<f32>>,
+    ) -> anyhow::Result<Porcupine> {
         let selected_keywords = self.keyword_pairs()?;
         let keyword_paths = selected_keywords
             .iter()
             .map(|(_, path)| path)
             .collect::<Vec<_>>();
+        self.build_porcupine_from_paths(&keyword_paths, sensitivities)
+    }
 
+    fn build_porcupine_from_paths(
+        &self,
+        keyword_paths: &[&PathBuf],
+        sensitivities: Option<&Vec<f32>>,
+    ) -> anyhow::Result<Porcupine> {
         let mut porcupine_builder =
-            PorcupineBuilder::new_with_keyword_paths(&self.access_key, &keyword_paths);
-        if let Some(sensitivities) = &self.sensitivities {
+            PorcupineBuilder::new_with_keyword_paths(&self.access_key, keyword_paths);
+        if let Some(sensitivities) = sensitivities {
             info!("Applying sensitivities {:?}", sensitivities);
             porcupine_builder.sensitivities(sensitivities);
         }
@@ -203,11 +614,380 @@ impl PicovoiceConfig {
     }
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KeywordSettings {
+    pub role: KeywordRole,
+    /// Message published by keywords with command role
+    pub command: Option<KeywordCommand>,
+    /// Speech within this window after a recording finishes starts a follow up recording
+    pub follow_up_window_ms: Option<u64>,
+    /// Keyword is detected even while the robot is speaking
+    pub allow_while_speaking: bool,
+    /// ISO-639-1 language of recordings or "auto". Defaults to English
+    pub language: Option<String>,
+    /// Spellings of the keyword per language used by wake word validation
+    pub spellings: HashMap<String, Vec<String>>,
+    /// Spellings accepted by wake word validation in any language
+    pub aliases: Vec<String>,
+    /// Overrides `picovoice.validation_similarity_threshold`
+    pub validation_similarity_threshold: Option<f32>,
+    /// Run second stage validation for detections of this keyword
+    pub validate: bool,
+    /// Porcupine sensitivity. Takes precedence over `picovoice.sensitivities`
+    pub sensitivity: Option<f32>,
+}
+
+impl Default for KeywordSettings {
+    fn default() -> Self {
+        Self {
+            role: KeywordRole::default(),
+            command: None,
+            follow_up_window_ms: None,
+            allow_while_speaking: false,
+            language: None,
+            spellings: HashMap::new(),
+            aliases: vec![],
+            validation_similarity_threshold: None,
+            validate: true,
+            sensitivity: None,
+        }
+    }
+}
+
+impl KeywordSettings {
+    /// Language passed to Whisper. None lets Whisper detect it
+    pub fn transcription_language(&self) -> Option<&str> {
+        match self.language.as_deref() {
+            Some(AUTO_LANGUAGE) => None,
+            Some(language) => Some(language),
+            None => Some(VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE),
+        }
+    }
+
+    /// Lowercase spellings of the keyword expected in transcripts
+    /// All languages are accepted when language is detected automatically
+    pub fn spellings(&self, keyword: &str) -> Vec<String> {
+        let language_spellings = self
+            .spellings
+            .iter()
+            .filter(|(language, _)| {
+                self.transcription_language()
+                    .map_or(true, |selected| selected == language.as_str())
+            })
+            .flat_map(|(_, spellings)| spellings);
+        std::iter::once(keyword)
+            .chain(self.aliases.iter().map(String::as_str))
+            .chain(language_spellings.map(String::as_str))
+            .map(str::to_lowercase)
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeywordRole {
+    /// Start recording and transcribe it
+    #[default]
+    WakeWord,
+    /// Publish stop command without recording
+    /// Works during active recordings and while the robot is speaking
+    Interrupt,
+    /// Publish configured command without recording or transcription
+    Command,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeywordCommand {
+    /// Full zenoh key, not prefixed
+    pub topic: String,
+    /// JSON template. `${wake_word}`, `${timestamp}` and `${direction_of_arrival}`
+    /// are replaced with JSON values so they shouldn't be quoted
+    pub payload: String,
+}
+
+impl KeywordCommand {
+    pub fn render_payload(
+        &self,
+        wake_word: &str,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        direction_of_arrival: Option<i32>,
+    ) -> anyhow::Result<String> {
+        let payload = self
+            .payload
+            .replace("${wake_word}", &serde_json::to_string(wake_word)?)
+            .replace("${timestamp}", &serde_json::to_string(&timestamp)?)
+            .replace(
+                "${direction_of_arrival}",
+                &serde_json::to_string(&direction_of_arrival)?,
+            );
+        serde_json::from_str::<serde_json::Value>(&payload)
+            .with_context(|| format!("Command payload is not valid JSON {:?}", payload))?;
+        Ok(payload)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ValidationBackendConfig {
+    /// Transcribe detection using OpenAI Whisper
+    #[default]
+    Whisper,
+    /// Offline MFCC and DTW comparison with enrolled samples
+    Template {
+        /// Contains a directory of WAV samples for each wake word
+        enrollment_dir: PathBuf,
+        /// Minimum score `1 / (1 + d)` where `d` is the DTW distance to the closest
+        /// enrolled sample averaged per template frame. 0.15 allows a distance of about 5.7
+        score_threshold: f32,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationFailurePolicy {
+    /// Keep recording as if validation succeeded
+    #[default]
+    Accept,
+    /// Drop recording with validation failed end reason
+    Reject,
+    /// Don't validate wake words at all
+    Skip,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DatasetCaptureConfig {
+    /// WAV files and manifest are stored here
+    pub directory: PathBuf,
+    /// Fraction between 0 and 1 of accepted detections that are also saved
+    #[serde(default = "default_accepted_sample_rate")]
+    pub accepted_sample_rate: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeakingPolicy {
+    /// Detect wake words as usual
+    #[default]
+    Ignore,
+    /// Don't detect any wake words
+    Suppress,
+    /// Use separate porcupine instance with `speaking_sensitivities`
+    ReducedSensitivity,
+    /// Only detect keywords with `allow_while_speaking`
+    BargeInOnly,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceProbabilityAggregation {
+    /// Highest probability in the window so short speech isn't hidden
+    #[default]
+    Max,
+    Mean,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceProbabilityPublishMode {
+    /// Publish every window
+    #[default]
+    Always,
+    /// Publish only while a recording is active
+    WhileRecording,
+    /// Publish when probability moved by at least `change_threshold` since last publish
+    OnChange,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VoiceProbabilityTelemetryConfig {
+    /// Frames within this window are aggregated into one message. 0 publishes every frame
+    pub interval_ms: u64,
+    pub aggregation: VoiceProbabilityAggregation,
+    pub mode: VoiceProbabilityPublishMode,
+    /// Used by the `on_change` mode
+    pub change_threshold: f32,
+    /// Also publish a text bar on the pretty print topic
+    pub pretty_print: bool,
+}
+
+impl Default for VoiceProbabilityTelemetryConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 0,
+            aggregation: VoiceProbabilityAggregation::default(),
+            mode: VoiceProbabilityPublishMode::default(),
+            change_threshold: 0.1,
+            pretty_print: true,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct WakeWordOpenaiConfig {
     pub api_key: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Address of HTTP server with `/metrics` endpoint. Disabled if not set
+    pub http_address: Option<SocketAddr>,
+    /// How often metrics are published on the telemetry topic. 0 disables publishing
+    pub telemetry_interval_ms: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            http_address: None,
+            telemetry_interval_ms: 10000,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn telemetry_interval(&self) -> Option<Duration> {
+        if self.telemetry_interval_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.telemetry_interval_ms))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TranscriptionConfig {
+    /// Timeout of a single transcription request
+    pub timeout_ms: u64,
+    /// Number of times a request is repeated after a timeout or network error
+    pub max_retries: u32,
+    /// Delay before first retry. Doubled after each retry
+    pub retry_backoff_ms: u64,
+    /// Recordings not transcribed within this time after they ended are dropped
+    /// Includes time waiting in queue and the transcription request itself
+    pub max_queue_age_ms: u64,
+    /// Number of recordings that can wait for transcription
+    pub queue_size: usize,
+    /// Request verbose responses with language, duration and segments
+    pub verbose: bool,
+    /// Transcripts where all segments exceed this no speech probability are suppressed
+    /// Only applies to verbose responses
+    pub no_speech_probability_threshold: Option<f32>,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 15000,
+            max_retries: 2,
+            retry_backoff_ms: 500,
+            max_queue_age_ms: 30000,
+            queue_size: 100,
+            verbose: false,
+            no_speech_probability_threshold: None,
+        }
+    }
+}
+
+impl TranscriptionConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms)
+    }
+
+    pub fn max_queue_age(&self) -> Duration {
+        Duration::from_millis(self.max_queue_age_ms)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TranscriptFilterConfig {
+    /// Known hallucination phrases. Compared against whole transcript ignoring case and punctuation
+    pub blocklist: Vec<String>,
+    pub min_word_count: usize,
+    /// Maximum number of times a word or phrase can be repeated back to back
+    pub max_repeated_words: Option<usize>,
+    /// Transcripts too long for the duration of the recording are likely hallucinations
+    pub max_characters_per_second: Option<f32>,
+    /// Regex rules per wake word
+    pub wake_words: HashMap<String, WakeWordTranscriptFilterConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct WakeWordTranscriptFilterConfig {
+    /// If not empty transcript has to match at least one of these patterns
+    pub allow: Vec<String>,
+    /// Transcript can't match any of these patterns
+    pub deny: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TranscriptRouteConfig {
+    pub name: String,
+    /// Full zenoh key, not prefixed
+    pub topic: String,
+    /// Route only applies to these wake words. Applies to all if empty
+    #[serde(default)]
+    pub wake_words: Vec<String>,
+    /// Regex matched against transcript. Named capture groups are published under `captures`
+    pub pattern: Option<String>,
+    /// Words or phrases matched on word boundaries ignoring case
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SystemPromptConfig {
+    /// Used for wake words without entry in `app.system_prompts`
+    pub default_prompt: Option<String>,
+    pub robot_name: String,
+    /// Words Whisper should expect, for example names
+    pub vocabulary: Vec<String>,
+    /// Number of transcripts kept for `${recent_transcripts}`
+    pub recent_transcripts: usize,
+}
+
+impl Default for SystemPromptConfig {
+    fn default() -> Self {
+        Self {
+            default_prompt: None,
+            robot_name: String::new(),
+            vocabulary: vec![],
+            recent_transcripts: 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IntentConfig {
+    /// Custom slot values referenced as `{name}` in grammars
+    /// `number` and `color` are built in and can't be redefined
+    pub slots: HashMap<String, Vec<String>>,
+    /// Checked in order, first match wins
+    pub intents: Vec<IntentGrammarConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IntentGrammarConfig {
+    pub name: String,
+    /// `word`, `(a|b)` alternatives, `[word]` optional and `{slot}`
+    /// A slot can appear in several alternatives but only be filled once per match
+    pub grammars: Vec<String>,
+    /// Intent only applies to these wake words. Applies to all if empty
+    #[serde(default)]
+    pub wake_words: Vec<String>,
+}
+
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct WakewordZenohConfig {
     #[serde(default)]
@@ -252,4 +1032,279 @@ mod tests {
             .unwrap();
         builder.try_deserialize::<WakewordConfig>().unwrap();
     }
+
+    #[test]
+    fn wav_topic_keeps_legacy_key_as_prefix() {
+        let builder = Config::builder()
+            .add_source(config::File::from_str(
+                DEFAULT_CONFIG,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+        let app = builder.try_deserialize::<WakewordConfig>().unwrap().app;
+        let recording_id = Uuid::nil();
+        assert_eq!(
+            app.get_wake_word_audio_recording_wav_topic(),
+            "wakeword/event/wake_word_audio_wav"
+        );
+        assert_eq!(
+            app.get_wake_word_audio_recording_wav_topic_for_recording(&recording_id),
+            "wakeword/event/wake_word_audio_wav/00000000-0000-0000-0000-000000000000"
+        );
+        assert!(app.publish_legacy_wav_topic);
+    }
+
+    #[test]
+    fn dismiss_keywords_seed_dismiss_phrases() {
+        let builder = Config::builder()
+            .add_source(config::File::from_str(
+                DEFAULT_CONFIG,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+        let mut config = builder.try_deserialize::<WakewordConfig>().unwrap();
+        config.app.dismiss_phrases = vec![String::from("never mind"), String::from("Dismiss")];
+        config.picovoice.dismiss_keyword = Some(String::from("dismiss"));
+        config.picovoice.dismiss_keywords = vec![String::from("Cancel that")];
+        // configured phrase already covers legacy keyword ignoring case
+        assert_eq!(
+            config.dismiss_phrases(),
+            vec!["never mind", "Dismiss", "Cancel that"]
+        );
+
+        config.app.dismiss_phrases.clear();
+        config.picovoice.dismiss_keywords.clear();
+        assert_eq!(config.dismiss_phrases(), vec!["dismiss"]);
+    }
+
+    #[test]
+    fn wake_word_filters_must_be_keywords() {
+        let builder = Config::builder()
+            .add_source(config::File::from_str(
+                DEFAULT_CONFIG,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+        let mut config = builder.try_deserialize::<WakewordConfig>().unwrap();
+        // built in keyword files aren't needed to compare names
+        config.picovoice.keywords = None;
+        // "Hey Hopper" in the route and intent matches the lowercased keyword_paths key
+        config.validate_wake_word_filters().unwrap();
+
+        let mut unknown_route = config.clone();
+        unknown_route.transcript_routes[0].wake_words = vec![String::from("Hey Hoppa")];
+        let err = unknown_route.validate_wake_word_filters().unwrap_err();
+        assert!(err.to_string().contains("Hey Hoppa"));
+
+        let face = config
+            .intents
+            .intents
+            .iter_mut()
+            .find(|intent| intent.name == "face")
+            .unwrap();
+        face.wake_words = vec![String::from("Hey Hoppa")];
+        let err = config.validate_wake_word_filters().unwrap_err();
+        assert!(err.to_string().contains("Intent \"face\""));
+    }
+
+    #[test]
+    fn validated_keywords_skip_dismiss_and_other_roles() {
+        let builder = Config::builder()
+            .add_source(config::File::from_str(
+                DEFAULT_CONFIG,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+        let mut config = builder
+            .try_deserialize::<WakewordConfig>()
+            .unwrap()
+            .picovoice;
+        config.keywords = None;
+        let mut validated_keywords = config.validated_keywords().unwrap();
+        validated_keywords.sort();
+        // songbird opts out of validation
+        assert_eq!(
+            validated_keywords,
+            vec!["hey hopper", "hopper stop", "wintermute"]
+        );
+
+        config.keyword_settings.insert(
+            String::from("hopper stop"),
+            KeywordSettings {
+                role: KeywordRole::Interrupt,
+                ..Default::default()
+            },
+        );
+        assert!(!config
+            .validated_keywords()
+            .unwrap()
+            .contains(&String::from("hopper stop")));
+
+        config.validation_failure_policy = ValidationFailurePolicy::Skip;
+        assert!(config.validated_keywords().unwrap().is_empty());
+    }
+
+    /// Config without the tuned sensitivities of the default config
+    fn picovoice_config() -> PicovoiceConfig {
+        PicovoiceConfig {
+            access_key: String::from("ACCESS_KEY"),
+            keywords: None,
+            keyword_paths: None,
+            model_path: None,
+            sensitivities: None,
+            audio_device_index: None,
+            dismiss_keyword: None,
+            dismiss_keywords: vec![],
+            keyword_settings: HashMap::new(),
+            speaking_policy: SpeakingPolicy::default(),
+            speaking_sensitivities: None,
+            validation_similarity_threshold: default_validation_similarity_threshold(),
+            validation_backend: ValidationBackendConfig::default(),
+            validation_failure_policy: ValidationFailurePolicy::default(),
+            validation_deadline_ms: default_validation_deadline_ms(),
+            dataset_capture: None,
+            stage_timings: false,
+            voice_probability_telemetry: VoiceProbabilityTelemetryConfig::default(),
+            cobra_lib_path: None,
+            porcupine_lib_path: None,
+            recorder_lib_path: None,
+        }
+    }
+
+    #[test]
+    fn keyword_sensitivities() {
+        let mut config = picovoice_config();
+        let keywords = vec![
+            (String::from("hey hopper"), PathBuf::from("hey_hopper.ppn")),
+            (String::from("wintermute"), PathBuf::from("wintermute.ppn")),
+            (String::from("dismiss"), PathBuf::from("dismiss.ppn")),
+        ];
+        assert_eq!(config.detection_sensitivities(&keywords), None);
+
+        config.sensitivities = Some(vec![0.2, 0.3]);
+        assert_eq!(
+            config.detection_sensitivities(&keywords),
+            Some(vec![0.2, 0.3])
+        );
+
+        config.keyword_settings.insert(
+            String::from("wintermute"),
+            KeywordSettings {
+                sensitivity: Some(0.7),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            config.detection_sensitivities(&keywords),
+            Some(vec![0.2, 0.7, DEFAULT_SENSITIVITY])
+        );
+    }
+
+    #[test]
+    fn speaking_sensitivities_never_exceed_normal() {
+        let mut config = picovoice_config();
+        let keywords = vec![
+            (String::from("hey hopper"), PathBuf::from("hey_hopper.ppn")),
+            (String::from("wintermute"), PathBuf::from("wintermute.ppn")),
+        ];
+        config.sensitivities = Some(vec![0.2]);
+        config.keyword_settings.insert(
+            String::from("wintermute"),
+            KeywordSettings {
+                sensitivity: Some(0.8),
+                ..Default::default()
+            },
+        );
+        // derived from tuned and per keyword sensitivities instead of library default
+        assert_eq!(
+            config.speaking_sensitivities(&keywords).unwrap(),
+            vec![0.1, 0.4]
+        );
+
+        config.speaking_sensitivities = Some(vec![0.2, 0.3]);
+        assert_eq!(
+            config.speaking_sensitivities(&keywords).unwrap(),
+            vec![0.2, 0.3]
+        );
+
+        config.speaking_sensitivities = Some(vec![0.3, 0.3]);
+        assert!(config.speaking_sensitivities(&keywords).is_err());
+
+        config.speaking_sensitivities = Some(vec![0.1]);
+        assert!(config.speaking_sensitivities(&keywords).is_err());
+    }
+
+    #[test]
+    fn keyword_languages() {
+        let settings = KeywordSettings::default();
+        assert_eq!(settings.transcription_language(), Some("en"));
+        assert_eq!(settings.spellings("Hey Hopper"), vec!["hey hopper"]);
+
+        let mut spellings = HashMap::new();
+        spellings.insert(String::from("cs"), vec![String::from("Hej Hopře")]);
+        spellings.insert(String::from("de"), vec![String::from("Hei Hopper")]);
+        let settings = KeywordSettings {
+            language: Some(String::from("cs")),
+            spellings,
+            ..Default::default()
+        };
+        assert_eq!(settings.transcription_language(), Some("cs"));
+        assert_eq!(
+            settings.spellings("Hey Hopper"),
+            vec!["hey hopper", "hej hopře"]
+        );
+
+        let settings = KeywordSettings {
+            language: Some(String::from("auto")),
+            ..settings
+        };
+        assert_eq!(settings.transcription_language(), None);
+        assert_eq!(settings.spellings("Hey Hopper").len(), 3);
+    }
+
+    #[test]
+    fn render_command_payload() {
+        let command = KeywordCommand {
+            topic: String::from("hopper/control/stop"),
+            payload: String::from(
+                r#"{"keyword": ${wake_word}, "time": ${timestamp}, "doa": ${direction_of_arrival}}"#,
+            ),
+        };
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+
+        let payload = command
+            .render_payload("Hopper Stop", timestamp, Some(90))
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "keyword": "Hopper Stop",
+                "time": "2024-05-01T12:00:00Z",
+                "doa": 90
+            })
+        );
+
+        let payload = command
+            .render_payload("Hopper Stop", timestamp, None)
+            .unwrap();
+        assert!(payload.ends_with(r#""doa": null}"#));
+    }
+
+    #[test]
+    fn render_invalid_command_payload() {
+        let command = KeywordCommand {
+            topic: String::from("hopper/control/stop"),
+            payload: String::from(r#"{"keyword": "${wake_word}"}"#),
+        };
+        assert!(command
+            .render_payload("Hopper Stop", chrono::Utc::now(), None)
+            .is_err());
+    }
 }
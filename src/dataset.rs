@@ -0,0 +1,420 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    configuration::DatasetCaptureConfig,
+    messages::AudioSample,
+    metrics::{Metrics, MESSAGES_DROPPED},
+};
+
+const MANIFEST_FILE: &str = "manifest.jsonl";
+const STATISTICS_FILE: &str = "statistics.json";
+/// Detections waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetLabel {
+    /// Validation decided the wake word wasn't spoken
+    Rejected,
+    /// User dismissed the recording
+    Dismissed,
+    /// Randomly sampled detection that was accepted
+    Accepted,
+}
+
+impl DatasetLabel {
+    fn directory_name(&self) -> &'static str {
+        match self {
+            DatasetLabel::Rejected => "rejected",
+            DatasetLabel::Dismissed => "dismissed",
+            DatasetLabel::Accepted => "accepted",
+        }
+    }
+}
+
+/// Single line of the dataset manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatasetEntry {
+    pub recording_id: Uuid,
+    /// WAV path relative to the dataset directory
+    pub file: PathBuf,
+    pub wake_word: String,
+    pub label: DatasetLabel,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub sample_rate: u32,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct KeywordStatistics {
+    pub rejected: usize,
+    pub dismissed: usize,
+    pub accepted: usize,
+    pub duration_ms: u64,
+}
+
+/// Audio window around a detection waiting to be written
+pub struct DatasetSample {
+    pub recording_id: Uuid,
+    pub wake_word: String,
+    pub label: DatasetLabel,
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Queues audio windows around detections for the dataset writer thread
+///
+/// Queueing never blocks so it's safe to use from the audio thread
+#[derive(Clone)]
+pub struct DatasetRecorder {
+    sender: SyncSender<DatasetSample>,
+    accepted_sample_rate: f32,
+    metrics: Arc<Metrics>,
+}
+
+impl DatasetRecorder {
+    /// Starts writer thread that stops once every recorder is dropped
+    pub fn new(config: &DatasetCaptureConfig, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let writer = DatasetWriter {
+            directory: config.directory.clone(),
+        };
+        std::thread::Builder::new()
+            .name(String::from("dataset_writer"))
+            .spawn(move || writer.run(receiver))
+            .context("Failed to start dataset writer thread")?;
+        Ok(Self {
+            sender,
+            accepted_sample_rate: config.accepted_sample_rate,
+            metrics,
+        })
+    }
+
+    /// Recording ids are random so they are used to pick accepted samples
+    pub fn should_save_accepted(&self, recording_id: Uuid) -> bool {
+        let sample = (recording_id.as_u128() % 10_000) as f32 / 10_000.0;
+        sample < self.accepted_sample_rate
+    }
+
+    /// Samples are dropped and counted if the writer falls behind
+    pub fn save(&self, sample: DatasetSample) {
+        let label = sample.label;
+        match self.sender.try_send(sample) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                warn!("Dataset writer is behind. Dropping {:?} sample", label);
+                self.metrics.increment(
+                    &MESSAGES_DROPPED,
+                    &[("channel", "dataset"), ("kind", label.directory_name())],
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => error!("Dataset writer thread stopped"),
+        }
+    }
+
+    /// Save detection audio of a recording sent for transcription
+    ///
+    /// Follow up recordings don't have a detection
+    pub fn save_recording(&self, audio_sample: &AudioSample, label: DatasetLabel) {
+        if audio_sample.detection_window.is_empty() {
+            return;
+        }
+        self.save(DatasetSample {
+            recording_id: audio_sample.recording_id,
+            wake_word: audio_sample.wake_word.clone(),
+            label,
+            samples: audio_sample.detection_window.clone(),
+            sample_rate: audio_sample.sample_rate,
+            timestamp: audio_sample.timestamp,
+        });
+    }
+}
+
+/// Writes dataset samples to disk
+struct DatasetWriter {
+    directory: PathBuf,
+}
+
+impl DatasetWriter {
+    fn run(&self, receiver: Receiver<DatasetSample>) {
+        for sample in receiver {
+            if let Err(err) = self.save(&sample) {
+                warn!("Failed to save detection to dataset {:?}", err);
+            }
+        }
+    }
+
+    fn save(&self, sample: &DatasetSample) -> anyhow::Result<DatasetEntry> {
+        // wake word becomes a directory so it must not escape the dataset
+        let mut components = Path::new(&sample.wake_word).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            anyhow::bail!(
+                "Wake word {:?} is not a valid directory name",
+                sample.wake_word
+            );
+        }
+        let file = Path::new(sample.label.directory_name())
+            .join(&sample.wake_word)
+            .join(format!("{}.wav", sample.recording_id));
+        let path = self.directory.join(&file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create dataset directory {:?}", parent))?;
+        }
+        write_wav(&path, &sample.samples, sample.sample_rate)?;
+
+        let entry = DatasetEntry {
+            recording_id: sample.recording_id,
+            file,
+            wake_word: sample.wake_word.clone(),
+            label: sample.label,
+            timestamp: sample.timestamp,
+            sample_rate: sample.sample_rate,
+            duration_ms: sample.samples.len() as u64 * 1000 / sample.sample_rate as u64,
+        };
+        append_manifest(&self.directory, &entry)?;
+        info!(
+            "Saved {:?} detection of {:?} to dataset",
+            sample.label, sample.wake_word
+        );
+        Ok(entry)
+    }
+}
+
+fn write_wav(path: &Path, samples: &[i16], sample_rate: u32) -> anyhow::Result<()> {
+    let wavspec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer =
+        hound::WavWriter::create(path, wavspec).context("Failed to create dataset audio file")?;
+    for sample in samples {
+        writer
+            .write_sample(*sample)
+            .context("Failed to write sample")?;
+    }
+    writer.finalize().context("Failed to finalize WAV")?;
+    Ok(())
+}
+
+fn append_manifest(directory: &Path, entry: &DatasetEntry) -> anyhow::Result<()> {
+    let mut manifest = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(directory.join(MANIFEST_FILE))
+        .context("Failed to open dataset manifest")?;
+    writeln!(manifest, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+pub fn read_manifest(directory: &Path) -> anyhow::Result<Vec<DatasetEntry>> {
+    let manifest = fs::File::open(directory.join(MANIFEST_FILE))
+        .with_context(|| format!("Failed to open dataset manifest in {:?}", directory))?;
+    let mut entries = vec![];
+    for line in BufReader::new(manifest).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // partially written line if the process was killed while saving
+            Err(err) => warn!("Skipping invalid manifest line {:?}", err),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn keyword_statistics(entries: &[DatasetEntry]) -> BTreeMap<String, KeywordStatistics> {
+    let mut statistics: BTreeMap<String, KeywordStatistics> = BTreeMap::new();
+    for entry in entries {
+        let keyword_statistics = statistics.entry(entry.wake_word.clone()).or_default();
+        match entry.label {
+            DatasetLabel::Rejected => keyword_statistics.rejected += 1,
+            DatasetLabel::Dismissed => keyword_statistics.dismissed += 1,
+            DatasetLabel::Accepted => keyword_statistics.accepted += 1,
+        }
+        keyword_statistics.duration_ms += entry.duration_ms;
+    }
+    statistics
+}
+
+/// Copy captured dataset into `output` with manifest and per keyword statistics
+///
+/// Entries whose audio is missing are left out
+pub fn export_dataset(
+    directory: &Path,
+    output: &Path,
+) -> anyhow::Result<BTreeMap<String, KeywordStatistics>> {
+    if output.exists() && fs::read_dir(output)?.next().is_some() {
+        anyhow::bail!("Export directory {:?} is not empty", output);
+    }
+    let mut exported = vec![];
+    for entry in read_manifest(directory)? {
+        let source = directory.join(&entry.file);
+        if !source.exists() {
+            warn!("Skipping missing dataset file {:?}", source);
+            continue;
+        }
+        let destination = output.join(&entry.file);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&source, &destination).with_context(|| format!("Failed to copy {:?}", source))?;
+        exported.push(entry);
+    }
+    fs::create_dir_all(output)?;
+    for entry in &exported {
+        append_manifest(output, entry)?;
+    }
+
+    let statistics = keyword_statistics(&exported);
+    fs::write(
+        output.join(STATISTICS_FILE),
+        serde_json::to_string_pretty(&statistics)?,
+    )?;
+    Ok(statistics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wakeword_{}_{}", name, Uuid::new_v4()))
+    }
+
+    fn sample(wake_word: &str, label: DatasetLabel, samples: usize) -> DatasetSample {
+        DatasetSample {
+            recording_id: Uuid::new_v4(),
+            wake_word: wake_word.to_owned(),
+            label,
+            samples: vec![0; samples],
+            sample_rate: 16000,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    /// Recorder without writer thread so queued samples stay in `receiver`
+    fn recorder_without_writer(
+        accepted_sample_rate: f32,
+    ) -> (DatasetRecorder, Receiver<DatasetSample>, Arc<Metrics>) {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let metrics = Arc::new(Metrics::default());
+        let recorder = DatasetRecorder {
+            sender,
+            accepted_sample_rate,
+            metrics: metrics.clone(),
+        };
+        (recorder, receiver, metrics)
+    }
+
+    #[test]
+    fn accepted_sampling() {
+        let (recorder, _receiver, _) = recorder_without_writer(0.25);
+        let saved = (0..4000)
+            .filter(|_| recorder.should_save_accepted(Uuid::new_v4()))
+            .count();
+        assert!((800..1200).contains(&saved), "saved {}", saved);
+
+        let (recorder, _receiver, _) = recorder_without_writer(0.0);
+        assert!(!recorder.should_save_accepted(Uuid::nil()));
+    }
+
+    #[test]
+    fn full_queue_drops_new_samples() {
+        let (recorder, receiver, metrics) = recorder_without_writer(1.0);
+        for _ in 0..QUEUE_SIZE + 3 {
+            recorder.save(sample("Hey Hopper", DatasetLabel::Rejected, 160));
+        }
+        assert_eq!(receiver.try_iter().count(), QUEUE_SIZE);
+        let dropped = metrics
+            .snapshot()
+            .counters
+            .into_iter()
+            .find(|counter| {
+                counter.name == MESSAGES_DROPPED.name && counter.labels["channel"] == "dataset"
+            })
+            .map(|counter| counter.value);
+        assert_eq!(dropped, Some(3));
+
+        // writer stopping doesn't panic the caller
+        drop(receiver);
+        recorder.save(sample("Hey Hopper", DatasetLabel::Rejected, 160));
+    }
+
+    #[test]
+    fn rejects_wake_words_escaping_dataset() {
+        let directory = test_directory("escape");
+        let writer = DatasetWriter {
+            directory: directory.clone(),
+        };
+        for wake_word in ["../Hopper", "hey/hopper", "/tmp", "..", "."] {
+            assert!(
+                writer
+                    .save(&sample(wake_word, DatasetLabel::Dismissed, 160))
+                    .is_err(),
+                "{:?}",
+                wake_word
+            );
+        }
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn save_and_export() {
+        let directory = test_directory("dataset");
+        let output = test_directory("export");
+        let writer = DatasetWriter {
+            directory: directory.clone(),
+        };
+        let rejected = writer
+            .save(&sample("Hey Hopper", DatasetLabel::Rejected, 16000))
+            .unwrap();
+        writer
+            .save(&sample("Hey Hopper", DatasetLabel::Dismissed, 8000))
+            .unwrap();
+        let missing = writer
+            .save(&sample("Wintermute", DatasetLabel::Accepted, 16000))
+            .unwrap();
+        assert_eq!(rejected.duration_ms, 1000);
+        fs::remove_file(directory.join(&missing.file)).unwrap();
+
+        let statistics = export_dataset(&directory, &output).unwrap();
+        assert_eq!(
+            statistics.get("Hey Hopper"),
+            Some(&KeywordStatistics {
+                rejected: 1,
+                dismissed: 1,
+                accepted: 0,
+                duration_ms: 1500,
+            })
+        );
+        assert!(!statistics.contains_key("Wintermute"));
+        assert_eq!(read_manifest(&output).unwrap().len(), 2);
+        assert!(output.join(&rejected.file).exists());
+        assert!(output.join(STATISTICS_FILE).exists());
+
+        // refuse to mix with previous export
+        assert!(export_dataset(&directory, &output).is_err());
+    }
+}
@@ -0,0 +1,553 @@
+use anyhow::Context;
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::{configuration::IntentConfig, transcript_filter::normalize_transcript};
+
+const NUMBER_SLOT: &str = "number";
+const COLOR_SLOT: &str = "color";
+
+const UNITS: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: [&str; 8] = [
+    "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const COLORS: [&str; 10] = [
+    "red", "green", "blue", "yellow", "orange", "purple", "pink", "white", "black", "cyan",
+];
+
+/// Parsed grammar
+///
+/// `word` literal, `(a|b c)` alternatives, `[please]` optional, `{slot}` slot value
+#[derive(Debug, Clone, PartialEq)]
+enum GrammarNode {
+    Word(String),
+    Alternatives(Vec<Vec<GrammarNode>>),
+    Optional(Vec<GrammarNode>),
+    Slot(String),
+}
+
+struct GrammarParser<'a> {
+    tokens: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> GrammarParser<'a> {
+    fn parse(grammar: &'a str) -> anyhow::Result<Vec<Vec<GrammarNode>>> {
+        let mut parser = Self {
+            tokens: grammar.chars().peekable(),
+        };
+        let alternatives = parser.parse_alternatives(None)?;
+        if let Some(character) = parser.tokens.next() {
+            anyhow::bail!("Unexpected {:?}", character);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_alternatives(
+        &mut self,
+        closing: Option<char>,
+    ) -> anyhow::Result<Vec<Vec<GrammarNode>>> {
+        let mut alternatives = vec![self.parse_sequence(closing)?];
+        while self.tokens.peek() == Some(&'|') {
+            self.tokens.next();
+            alternatives.push(self.parse_sequence(closing)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, closing: Option<char>) -> anyhow::Result<Vec<GrammarNode>> {
+        let mut sequence = vec![];
+        while let Some(&character) = self.tokens.peek() {
+            match character {
+                '|' => break,
+                character if Some(character) == closing => break,
+                ')' | ']' | '}' => anyhow::bail!("Unexpected {:?}", character),
+                '(' => {
+                    self.tokens.next();
+                    let alternatives = self.parse_alternatives(Some(')'))?;
+                    self.expect(')')?;
+                    sequence.push(GrammarNode::Alternatives(alternatives));
+                }
+                '[' => {
+                    self.tokens.next();
+                    let alternatives = self.parse_alternatives(Some(']'))?;
+                    self.expect(']')?;
+                    let optional = if alternatives.len() == 1 {
+                        alternatives.into_iter().flatten().collect()
+                    } else {
+                        vec![GrammarNode::Alternatives(alternatives)]
+                    };
+                    sequence.push(GrammarNode::Optional(optional));
+                }
+                '{' => {
+                    self.tokens.next();
+                    let name = self.take_word();
+                    self.expect('}')?;
+                    if name.is_empty() {
+                        anyhow::bail!("Empty slot name");
+                    }
+                    sequence.push(GrammarNode::Slot(name));
+                }
+                character if character.is_whitespace() => {
+                    self.tokens.next();
+                }
+                _ => {
+                    let word = self.take_word();
+                    if word.is_empty() {
+                        anyhow::bail!("Unexpected {:?}", character);
+                    }
+                    sequence.push(GrammarNode::Word(normalize_transcript(&word)));
+                }
+            }
+        }
+        Ok(sequence)
+    }
+
+    fn take_word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&character) = self.tokens.peek() {
+            if character.is_alphanumeric() || character == '\'' || character == '_' {
+                word.push(character);
+                self.tokens.next();
+            } else {
+                break;
+            }
+        }
+        word
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        match self.tokens.next() {
+            Some(character) if character == expected => Ok(()),
+            Some(character) => anyhow::bail!("Expected {:?} found {:?}", expected, character),
+            None => anyhow::bail!("Expected {:?} found end of grammar", expected),
+        }
+    }
+}
+
+fn words_pattern<'a>(words: impl IntoIterator<Item = &'a str>) -> String {
+    let words: Vec<String> = words
+        .into_iter()
+        .map(|word| regex::escape(&normalize_transcript(word)).replace(' ', r"\s+"))
+        .collect();
+    format!(r"\b(?:{})\b", words.join("|"))
+}
+
+fn number_pattern() -> String {
+    format!(
+        r"(?:\d+|{}(?:\s+{})?|{})",
+        words_pattern(TENS),
+        words_pattern(UNITS[1..10].iter().copied()),
+        words_pattern(UNITS)
+    )
+}
+
+/// Converts spelled out numbers up to ninety nine into digits
+fn parse_number(value: &str) -> Option<u32> {
+    if let Ok(number) = value.parse() {
+        return Some(number);
+    }
+    let mut number = 0;
+    for word in value.split_whitespace() {
+        if let Some(unit) = UNITS.iter().position(|unit| *unit == word) {
+            number += unit as u32;
+        } else if let Some(tens) = TENS.iter().position(|tens| *tens == word) {
+            number += (tens as u32 + 2) * 10;
+        } else {
+            return None;
+        }
+    }
+    Some(number)
+}
+
+/// Most times a slot can be filled by a single match of `sequence`
+fn max_slot_occurrences(sequence: &[GrammarNode], occurrences: &mut HashMap<String, usize>) {
+    for node in sequence {
+        match node {
+            GrammarNode::Word(_) => (),
+            GrammarNode::Slot(name) => *occurrences.entry(name.clone()).or_default() += 1,
+            GrammarNode::Optional(sequence) => max_slot_occurrences(sequence, occurrences),
+            GrammarNode::Alternatives(alternatives) => {
+                // only one alternative matches so take the maximum
+                let mut most = HashMap::new();
+                for alternative in alternatives {
+                    let mut alternative_occurrences = HashMap::new();
+                    max_slot_occurrences(alternative, &mut alternative_occurrences);
+                    for (name, count) in alternative_occurrences {
+                        let most = most.entry(name).or_default();
+                        *most = count.max(*most);
+                    }
+                }
+                for (name, count) in most {
+                    *occurrences.entry(name).or_default() += count;
+                }
+            }
+        }
+    }
+}
+
+/// Compiled grammar
+///
+/// Slots are unnamed capture groups so that the same slot can be used in several alternatives
+struct GrammarPattern {
+    regex: Regex,
+    /// Slot name of each capture group in order
+    slot_names: Vec<String>,
+}
+
+struct Intent {
+    name: String,
+    wake_words: Vec<String>,
+    patterns: Vec<GrammarPattern>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IntentMatch<'a> {
+    pub intent: &'a str,
+    pub slots: HashMap<String, String>,
+}
+
+/// Matches transcripts against grammars from config without needing an LLM
+pub struct IntentParser {
+    slot_patterns: HashMap<String, String>,
+    intents: Vec<Intent>,
+}
+
+impl IntentParser {
+    pub fn new(config: &IntentConfig) -> anyhow::Result<Self> {
+        let mut slot_patterns = HashMap::new();
+        slot_patterns.insert(NUMBER_SLOT.to_owned(), number_pattern());
+        slot_patterns.insert(COLOR_SLOT.to_owned(), words_pattern(COLORS));
+        for (name, values) in &config.slots {
+            if slot_patterns.contains_key(name) {
+                anyhow::bail!("Slot {:?} is built in and can't be redefined", name);
+            }
+            if values.is_empty() {
+                anyhow::bail!("Slot {:?} has no values", name);
+            }
+            slot_patterns.insert(
+                name.clone(),
+                words_pattern(values.iter().map(String::as_str)),
+            );
+        }
+
+        let mut parser = Self {
+            slot_patterns,
+            intents: vec![],
+        };
+        for intent in &config.intents {
+            let patterns = intent
+                .grammars
+                .iter()
+                .map(|grammar| {
+                    parser
+                        .compile(grammar)
+                        .with_context(|| format!("Bad grammar {:?} for {:?}", grammar, intent.name))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            parser.intents.push(Intent {
+                name: intent.name.clone(),
+                // runtime wake words are the lowercased config keys
+                wake_words: intent
+                    .wake_words
+                    .iter()
+                    .map(|wake_word| wake_word.to_lowercase())
+                    .collect(),
+                patterns,
+            });
+        }
+        Ok(parser)
+    }
+
+    fn compile(&self, grammar: &str) -> anyhow::Result<GrammarPattern> {
+        let alternatives = GrammarParser::parse(grammar)?;
+        let mut occurrences = HashMap::new();
+        max_slot_occurrences(
+            &[GrammarNode::Alternatives(alternatives.clone())],
+            &mut occurrences,
+        );
+        if let Some((name, _)) = occurrences.iter().find(|(_, count)| **count > 1) {
+            anyhow::bail!("Slot {:?} can be filled more than once by one match", name);
+        }
+
+        let mut slot_names = vec![];
+        let pattern = format!(
+            r"^\s*{}\s*$",
+            self.alternatives_pattern(&alternatives, &mut slot_names)?
+        );
+        Ok(GrammarPattern {
+            regex: Regex::new(&pattern)?,
+            slot_names,
+        })
+    }
+
+    fn alternatives_pattern(
+        &self,
+        alternatives: &[Vec<GrammarNode>],
+        slot_names: &mut Vec<String>,
+    ) -> anyhow::Result<String> {
+        let alternatives = alternatives
+            .iter()
+            .map(|sequence| self.sequence_pattern(sequence, slot_names))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(format!("(?:{})", alternatives.join("|")))
+    }
+
+    /// Words are matched on word boundaries so whitespace between them can be optional
+    fn sequence_pattern(
+        &self,
+        sequence: &[GrammarNode],
+        slot_names: &mut Vec<String>,
+    ) -> anyhow::Result<String> {
+        let nodes = sequence
+            .iter()
+            .map(|node| self.node_pattern(node, slot_names))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(nodes.join(r"\s*"))
+    }
+
+    /// Slots are the only capturing groups, all other groups are non capturing
+    fn node_pattern(
+        &self,
+        node: &GrammarNode,
+        slot_names: &mut Vec<String>,
+    ) -> anyhow::Result<String> {
+        match node {
+            GrammarNode::Word(word) => Ok(words_pattern([word.as_str()])),
+            GrammarNode::Alternatives(alternatives) => {
+                self.alternatives_pattern(alternatives, slot_names)
+            }
+            GrammarNode::Optional(sequence) => Ok(format!(
+                "(?:{})?",
+                self.sequence_pattern(sequence, slot_names)?
+            )),
+            GrammarNode::Slot(name) => {
+                let pattern = self
+                    .slot_patterns
+                    .get(name)
+                    .with_context(|| format!("Unknown slot {:?}", name))?;
+                slot_names.push(name.clone());
+                Ok(format!("({})", pattern))
+            }
+        }
+    }
+
+    /// First intent matching the whole transcript
+    pub fn parse(&self, wake_word: &str, transcript: &str) -> Option<IntentMatch<'_>> {
+        let normalized = normalize_transcript(transcript);
+        self.intents
+            .iter()
+            .filter(|intent| {
+                intent.wake_words.is_empty() || intent.wake_words.iter().any(|w| w == wake_word)
+            })
+            .find_map(|intent| {
+                intent.patterns.iter().find_map(|pattern| {
+                    let captures = pattern.regex.captures(&normalized)?;
+                    let slots = pattern
+                        .slot_names
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, name)| {
+                            let value = captures.get(index + 1)?.as_str();
+                            let value = if name == NUMBER_SLOT {
+                                parse_number(value)
+                                    .map(|number| number.to_string())
+                                    .unwrap_or_else(|| value.to_owned())
+                            } else {
+                                value.to_owned()
+                            };
+                            Some((name.to_owned(), value))
+                        })
+                        .collect();
+                    Some(IntentMatch {
+                        intent: &intent.name,
+                        slots,
+                    })
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{IntentGrammarConfig, WakewordConfig};
+
+    fn intent(name: &str, grammars: &[&str]) -> IntentGrammarConfig {
+        IntentGrammarConfig {
+            name: name.to_owned(),
+            grammars: grammars.iter().map(|grammar| grammar.to_string()).collect(),
+            wake_words: vec![],
+        }
+    }
+
+    fn parser(intents: Vec<IntentGrammarConfig>) -> anyhow::Result<IntentParser> {
+        IntentParser::new(&IntentConfig {
+            slots: HashMap::new(),
+            intents,
+        })
+    }
+
+    #[test]
+    fn grammar_parsing() {
+        assert_eq!(
+            GrammarParser::parse("(sit|stand) {number}").unwrap(),
+            vec![vec![
+                GrammarNode::Alternatives(vec![
+                    vec![GrammarNode::Word(String::from("sit"))],
+                    vec![GrammarNode::Word(String::from("stand"))],
+                ]),
+                GrammarNode::Slot(String::from("number")),
+            ]]
+        );
+        assert!(GrammarParser::parse("(sit|stand down").is_err());
+        assert!(GrammarParser::parse("sit]").is_err());
+        assert!(GrammarParser::parse("{}").is_err());
+    }
+
+    #[test]
+    fn words_need_boundaries_and_whole_transcript() {
+        let parser = parser(vec![intent(
+            "posture",
+            &["[please] (sit|stand) (down|up) [please]"],
+        )])
+        .unwrap();
+        let intent = parser
+            .parse("Wintermute", "Please stand up, please.")
+            .unwrap();
+        assert_eq!(intent.intent, "posture");
+        assert!(intent.slots.is_empty());
+        assert!(parser.parse("Wintermute", "sit down now").is_none());
+        assert!(parser.parse("Wintermute", "sitdown").is_none());
+    }
+
+    #[test]
+    fn same_slot_in_alternatives() {
+        let parser = parser(vec![intent(
+            "move",
+            &["(walk forward {number} steps|turn {number} degrees)"],
+        )])
+        .unwrap();
+        assert_eq!(
+            parser
+                .parse("Hey Hopper", "turn ninety degrees")
+                .unwrap()
+                .slots["number"],
+            "90"
+        );
+        assert_eq!(
+            parser
+                .parse("Hey Hopper", "walk forward 3 steps")
+                .unwrap()
+                .slots["number"],
+            "3"
+        );
+    }
+
+    #[test]
+    fn same_slot_twice_in_one_match() {
+        let err = parser(vec![intent("count", &["count from {number} to {number}"])])
+            .err()
+            .unwrap();
+        assert!(format!("{:?}", err).contains("\"number\" can be filled more than once"));
+        // optional repetition can also fill it twice
+        assert!(parser(vec![intent("count", &["{color} [and {color}]"])]).is_err());
+    }
+
+    #[test]
+    fn built_in_slot_cant_be_redefined() {
+        let mut slots = HashMap::new();
+        slots.insert(String::from("number"), vec![String::from("a few")]);
+        let err = IntentParser::new(&IntentConfig {
+            slots,
+            intents: vec![],
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("built in"));
+    }
+
+    #[test]
+    fn custom_slot_and_wake_word_filter() {
+        let mut slots = HashMap::new();
+        slots.insert(
+            String::from("animation"),
+            vec![String::from("larson scanner"), String::from("smile")],
+        );
+        let mut face = intent("face", &["show [the] {animation} [animation]"]);
+        face.wake_words = vec![String::from("Hey Hopper")];
+        let parser = IntentParser::new(&IntentConfig {
+            slots,
+            intents: vec![face],
+        })
+        .unwrap();
+        let intent = parser
+            .parse("hey hopper", "Show the Larson  scanner animation!")
+            .unwrap();
+        assert_eq!(intent.slots["animation"], "larson scanner");
+        assert!(parser.parse("wintermute", "show smile").is_none());
+    }
+
+    #[test]
+    fn wake_word_filter_from_config() {
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(
+                include_str!("../config/settings.yaml"),
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize::<WakewordConfig>()
+            .unwrap();
+        // detections carry the keyword name as loaded from keyword_paths
+        let wake_word = config
+            .picovoice
+            .keyword_paths
+            .iter()
+            .flatten()
+            .map(|(keyword, _)| keyword.as_str())
+            .find(|keyword| keyword.eq_ignore_ascii_case("Hey Hopper"))
+            .unwrap();
+        let parser = IntentParser::new(&config.intents).unwrap();
+        let face = parser.parse(wake_word, "show smile").unwrap();
+        assert_eq!(face.intent, "face");
+        assert_eq!(face.slots["animation"], "smile");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("25"), Some(25));
+        assert_eq!(parse_number("twenty five"), Some(25));
+        assert_eq!(parse_number("twelve"), Some(12));
+        assert_eq!(parse_number("ninety nine"), Some(99));
+        assert_eq!(parse_number("a dozen"), None);
+    }
+
+    #[test]
+    fn unknown_slot() {
+        let err = parser(vec![intent("broken", &["play {song}"])])
+            .err()
+            .unwrap();
+        assert!(format!("{:?}", err).contains("Unknown slot \"song\""));
+    }
+}
@@ -1,28 +1,45 @@
 use anyhow::Context;
-use async_openai::{config::OpenAIConfig, Client};
 use cobra::Cobra;
 use porcupine::Porcupine;
 use pv_recorder::{PvRecorder, PvRecorderBuilder};
 use std::{
+    collections::HashMap,
     path::PathBuf,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
-use tokio::sync::{mpsc::error::TrySendError, oneshot::error::TryRecvError};
+use tokio::sync::oneshot::error::TryRecvError;
 use tracing::{info, warn};
+use uuid::Uuid;
 
 use crate::{
-    configuration::PicovoiceConfig,
+    backpressure::{Delivery, DeliveryPolicy, Outbox, SendOutcome},
+    configuration::{
+        KeywordRole, KeywordSettings, PicovoiceConfig, SpeakingPolicy, ValidationFailurePolicy,
+    },
+    dataset::{DatasetLabel, DatasetRecorder, DatasetSample},
+    metrics::{Metrics, DETECTIONS},
     respeaker::ReSpeakerCommander,
-    wakeword_validation::{ValidationStatus, WakeWordValidator},
-    WakewordError, HUMAN_SPEECH_DETECTION_PROBABILITY_THRESHOLD, HUMAN_SPEECH_DETECTION_TIMEOUT,
+    stage_timing::{
+        LatencyHistogram, StageTimer, STAGE_COBRA, STAGE_FRAME, STAGE_PORCUPINE,
+        STAGE_VALIDATION_BUFFER,
+    },
+    voice_telemetry::VoiceProbabilityAggregator,
+    wakeword_validation::{
+        ValidationBackend, ValidationReceiver, ValidationStatus, WakeWordValidator,
+    },
+    WakewordError, FOLLOW_UP_PRE_ROLL, HUMAN_SPEECH_DETECTION_PROBABILITY_THRESHOLD,
+    HUMAN_SPEECH_DETECTION_TIMEOUT,
 };
 use crate::{
     messages::{
-        AudioSample, DetectionEndReason, VoiceProbability, WakeWordDetection, WakeWordDetectionEnd,
+        AudioSample, DetectionEndReason, DismissRule, ExpectFollowUpCommand,
+        FollowUpRecordingStarted, KeywordCommandMessage, StopCommand, TranscriptionQueueSaturated,
+        ValidationDecision, ValidationResult, VoiceProbability, WakeWordDetection,
+        WakeWordDetectionEnd,
     },
     RECORDING_INITIAL_TIMEOUT,
 };
@@ -30,8 +47,114 @@ use crate::{
 pub enum AudioDetectorData {
     VoiceProbability(VoiceProbability),
     RecordingStarted(WakeWordDetection),
+    FollowUpRecordingStarted(FollowUpRecordingStarted),
     WakeWordDetected(WakeWordDetection),
     RecordingEnd(WakeWordDetectionEnd),
+    Stop(StopCommand),
+    Command(KeywordCommandMessage),
+    ValidationResult(ValidationResult),
+    StageTimings(HashMap<String, LatencyHistogram>),
+    TranscriptionQueueSaturated(TranscriptionQueueSaturated),
+}
+
+impl Delivery for AudioDetectorData {
+    fn delivery_policy(&self) -> DeliveryPolicy {
+        match self {
+            // next frame carries a fresh value
+            AudioDetectorData::VoiceProbability(_) | AudioDetectorData::StageTimings(_) => {
+                DeliveryPolicy::Droppable
+            }
+            AudioDetectorData::RecordingStarted(_)
+            | AudioDetectorData::FollowUpRecordingStarted(_)
+            | AudioDetectorData::WakeWordDetected(_)
+            | AudioDetectorData::RecordingEnd(_)
+            | AudioDetectorData::Stop(_)
+            | AudioDetectorData::Command(_)
+            | AudioDetectorData::ValidationResult(_)
+            | AudioDetectorData::TranscriptionQueueSaturated(_) => DeliveryPolicy::Required,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            AudioDetectorData::VoiceProbability(_) => "voice_probability",
+            AudioDetectorData::RecordingStarted(_) => "recording_started",
+            AudioDetectorData::FollowUpRecordingStarted(_) => "follow_up_recording_started",
+            AudioDetectorData::WakeWordDetected(_) => "wake_word_detected",
+            AudioDetectorData::RecordingEnd(_) => "recording_end",
+            AudioDetectorData::Stop(_) => "stop",
+            AudioDetectorData::Command(_) => "command",
+            AudioDetectorData::ValidationResult(_) => "validation_result",
+            AudioDetectorData::StageTimings(_) => "stage_timings",
+            AudioDetectorData::TranscriptionQueueSaturated(_) => "transcription_queue_saturated",
+        }
+    }
+}
+
+impl Delivery for AudioSample {
+    fn delivery_policy(&self) -> DeliveryPolicy {
+        DeliveryPolicy::Required
+    }
+
+    fn kind(&self) -> &'static str {
+        "recording"
+    }
+}
+
+/// Whether the robot is speaking
+///
+/// Expires unless refreshed so that a TTS node that died mid sentence can't suppress detection forever
+pub struct SpeakingFlag {
+    reference: Instant,
+    /// Milliseconds after `reference` until which the robot is speaking
+    speaking_until_ms: AtomicU64,
+}
+
+impl Default for SpeakingFlag {
+    fn default() -> Self {
+        Self {
+            reference: Instant::now(),
+            speaking_until_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SpeakingFlag {
+    pub fn set(&self, speaking: bool, timeout: Duration) {
+        self.set_at(Instant::now(), speaking, timeout);
+    }
+
+    fn set_at(&self, now: Instant, speaking: bool, timeout: Duration) {
+        let speaking_until_ms = if speaking {
+            (now.duration_since(self.reference) + timeout).as_millis() as u64
+        } else {
+            0
+        };
+        self.speaking_until_ms
+            .store(speaking_until_ms, Ordering::Relaxed);
+    }
+
+    fn is_speaking_at(&self, now: Instant) -> bool {
+        (now.duration_since(self.reference).as_millis() as u64)
+            < self.speaking_until_ms.load(Ordering::Relaxed)
+    }
+}
+
+/// Handles shared between listener and the rest of the application
+#[derive(Clone)]
+pub struct ListenerSharedState {
+    /// When this flag is true do not listen to audio
+    pub privacy_mode_flag: Arc<AtomicBool>,
+    /// Follow up requested by downstream consumers
+    pub follow_up_request: Arc<Mutex<Option<ExpectFollowUpCommand>>>,
+    /// Set while the robot is speaking through its speaker
+    pub speaking_flag: Arc<SpeakingFlag>,
+    /// Last recording that was sent for transcription
+    /// Shared so that follow up requests still work after the listener is rebuilt
+    pub last_finished_recording: Arc<Mutex<Option<FinishedRecording>>>,
+    pub metrics: Arc<Metrics>,
+    /// Shared so that rebuilt listeners reuse the same writer thread
+    pub dataset_recorder: Option<DatasetRecorder>,
 }
 
 pub struct Listener {
@@ -39,19 +162,32 @@ pub struct Listener {
     recorder: PvRecorder,
     /// WakeWord detector
     porcupine: Porcupine,
+    /// WakeWord detector used while robot is speaking
+    speaking_porcupine: Option<Porcupine>,
     /// Human speech detector
     cobra: Cobra,
     /// WakeWord models
     selected_keywords: Vec<(String, PathBuf)>,
-    /// Keyword used for dismiss events
-    dismiss_keyword: Option<String>,
+    /// Keywords used for dismiss events
+    dismiss_keywords: Vec<String>,
+    /// Per keyword settings
+    keyword_settings: HashMap<String, KeywordSettings>,
     /// Sending raw audio recordings
-    audio_sample_sender: tokio::sync::mpsc::Sender<AudioSample>,
+    audio_sample_outbox: Outbox<AudioSample>,
     /// Sending wakeword events
-    audio_detector_data: tokio::sync::mpsc::Sender<AudioDetectorData>,
+    event_outbox: Outbox<AudioDetectorData>,
+    metrics: Arc<Metrics>,
     /// Privacy mode
     /// When this flag is true do not listen to audio
     privacy_mode_flag: Arc<AtomicBool>,
+    /// Follow up requested by downstream consumers
+    follow_up_request: Arc<Mutex<Option<ExpectFollowUpCommand>>>,
+    /// Set while the robot is speaking through its speaker
+    speaking_flag: Arc<SpeakingFlag>,
+    speaking_policy: SpeakingPolicy,
+    validation_similarity_threshold: f32,
+    /// Speaking state seen in previous frame
+    was_speaking: bool,
 
     last_human_speech_detected: Instant,
     // currently held audio samples
@@ -65,7 +201,21 @@ pub struct Listener {
 
     /// wake word validation state
     wake_word_validator: WakeWordValidator,
-    wake_word_validation_future: Option<tokio::sync::oneshot::Receiver<bool>>,
+    wake_word_validation_future: Option<PendingValidation>,
+    validation_failure_policy: ValidationFailurePolicy,
+    validation_deadline: Duration,
+
+    /// Saves rejected and dismissed detections
+    dataset_recorder: Option<DatasetRecorder>,
+    /// Per stage frame processing time. Only collected if enabled
+    stage_timer: Option<StageTimer>,
+    /// Limits how often voice probability is published
+    voice_probability_aggregator: VoiceProbabilityAggregator,
+
+    /// Last recording that was sent for transcription
+    last_finished_recording: Arc<Mutex<Option<FinishedRecording>>>,
+    /// Speech during this window starts a new recording without wake word
+    follow_up_window: Option<FollowUpWindow>,
 }
 
 impl Listener {
@@ -73,25 +223,26 @@ impl Listener {
         config: PicovoiceConfig,
         audio_sample_sender: tokio::sync::mpsc::Sender<AudioSample>,
         audio_detector_data: tokio::sync::mpsc::Sender<AudioDetectorData>,
-        privacy_mode_flag: Arc<AtomicBool>,
+        shared_state: ListenerSharedState,
         respeaker_commander: ReSpeakerCommander,
-        open_ai_client: Client<OpenAIConfig>,
+        validation_backend: Arc<dyn ValidationBackend>,
     ) -> anyhow::Result<Self> {
+        let ListenerSharedState {
+            privacy_mode_flag,
+            follow_up_request,
+            speaking_flag,
+            last_finished_recording,
+            metrics,
+            dataset_recorder,
+        } = shared_state;
         let selected_keywords = config.keyword_pairs()?;
 
         info!("Configuring porcupine");
         let porcupine = config.build_porcupine()?;
+        let speaking_porcupine = config.build_speaking_porcupine()?;
 
         info!("Configuring cobra");
-        let cobra = if let Some(cobra_lib_path) = config.cobra_lib_path {
-            Cobra::new_with_library(config.access_key, cobra_lib_path)
-                .map_err(WakewordError::CobraError)
-                .context("Failed to create Cobra")?
-        } else {
-            Cobra::new(config.access_key)
-                .map_err(WakewordError::CobraError)
-                .context("Failed to create Cobra")?
-        };
+        let cobra = config.build_cobra()?;
 
         info!("Configuring recorder");
         let mut recorder_builder = PvRecorderBuilder::new(porcupine.frame_length() as i32);
@@ -115,28 +266,54 @@ impl Listener {
         let listener = Self {
             recorder,
             porcupine,
+            speaking_porcupine,
             cobra,
             selected_keywords,
-            dismiss_keyword: config.dismiss_keyword.clone(),
-            audio_sample_sender,
-            audio_detector_data,
+            dismiss_keywords: config.all_dismiss_keywords(),
+            keyword_settings: config.keyword_settings.clone(),
+            audio_sample_outbox: Outbox::new("audio_sample", audio_sample_sender, metrics.clone()),
+            event_outbox: Outbox::new("audio_detector_event", audio_detector_data, metrics.clone()),
+            metrics,
             privacy_mode_flag,
+            follow_up_request,
+            speaking_flag,
+            speaking_policy: config.speaking_policy,
+            validation_similarity_threshold: config.validation_similarity_threshold,
+            was_speaking: false,
             audio_buffer: vec![],
             // doesn't matter is we starting it to now
             last_human_speech_detected: Instant::now(),
             recording_status: RecordingStatus::NotActive,
             respeaker_commander,
-            wake_word_validator: WakeWordValidator::new(open_ai_client, sample_rate),
+            wake_word_validator: WakeWordValidator::new(validation_backend, sample_rate),
             wake_word_validation_future: None,
+            validation_failure_policy: config.validation_failure_policy,
+            validation_deadline: config.validation_deadline(),
+            dataset_recorder,
+            stage_timer: config
+                .stage_timings
+                .then(|| StageTimer::new(Instant::now())),
+            voice_probability_aggregator: VoiceProbabilityAggregator::new(
+                &config.voice_probability_telemetry,
+            ),
+            last_finished_recording,
+            follow_up_window: None,
         };
 
         Ok(listener)
     }
 
     /// detect if wake word is present in sample
-    fn detect_wake_word(&self, audio_frame: &[i16]) -> anyhow::Result<Option<String>> {
-        let keyword_index = self
-            .porcupine
+    fn detect_wake_word(
+        &self,
+        audio_frame: &[i16],
+        speaking: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let porcupine = match &self.speaking_porcupine {
+            Some(speaking_porcupine) if speaking => speaking_porcupine,
+            _ => &self.porcupine,
+        };
+        let keyword_index = porcupine
             .process(audio_frame)
             .context("Failed to process audio frame")?;
 
@@ -148,16 +325,98 @@ impl Listener {
                 .context("Keyword index unknown")?
                 .0
                 .clone();
+            if speaking && !self.allowed_while_speaking(&wake_word) {
+                info!("Ignoring {:?} because robot is speaking", wake_word);
+                return Ok(None);
+            }
             Ok(Some(wake_word))
         } else {
             Ok(None)
         }
     }
 
-    fn send_event(&self, event: AudioDetectorData) -> anyhow::Result<()> {
-        if let Err(TrySendError::Closed(_)) = self.audio_detector_data.try_send(event) {
-            anyhow::bail!("Audio detector channel closed");
+    fn keyword_role(&self, wake_word: &str) -> KeywordRole {
+        self.keyword_settings
+            .get(wake_word)
+            .map(|settings| settings.role)
+            .unwrap_or_default()
+    }
+
+    fn allowed_while_speaking(&self, wake_word: &str) -> bool {
+        // interrupting the robot is the point of interrupt keywords
+        if self.keyword_role(wake_word) == KeywordRole::Interrupt {
+            return true;
+        }
+        match self.speaking_policy {
+            SpeakingPolicy::Ignore | SpeakingPolicy::ReducedSensitivity => true,
+            SpeakingPolicy::Suppress => false,
+            SpeakingPolicy::BargeInOnly => self
+                .keyword_settings
+                .get(wake_word)
+                .is_some_and(|settings| settings.allow_while_speaking),
+        }
+    }
+
+    fn send_stop_command(
+        &mut self,
+        wake_word: String,
+        ts_now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        info!("Detected interrupt keyword {:?}", wake_word);
+        let event = AudioDetectorData::Stop(StopCommand::new(
+            wake_word,
+            ts_now,
+            self.recording_status.recording_id(),
+        ));
+        self.send_event(event)
+    }
+
+    fn send_keyword_command(
+        &mut self,
+        wake_word: &str,
+        ts_now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        info!("Detected command keyword {:?}", wake_word);
+        let command = self
+            .keyword_settings
+            .get(wake_word)
+            .and_then(|settings| settings.command.as_ref())
+            .context("Command keyword without command")?;
+        let direction_of_arrival = self.respeaker_commander.read_direction();
+        match command.render_payload(wake_word, ts_now, direction_of_arrival) {
+            Ok(payload) => {
+                let event = AudioDetectorData::Command(KeywordCommandMessage {
+                    topic: command.topic.clone(),
+                    payload,
+                });
+                self.send_event(event)?;
+            }
+            Err(err) => {
+                warn!("Failed to render command for {:?} {:?}", wake_word, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if robot is speaking and update LEDs when it starts or stops
+    fn check_speaking(&mut self, instant_now: Instant) -> bool {
+        let speaking = self.speaking_flag.is_speaking_at(instant_now);
+        if speaking != self.was_speaking {
+            info!("Robot speaking status changed to {}", speaking);
+            if speaking {
+                self.respeaker_commander.speak();
+            } else if self.recording_status.active() {
+                self.respeaker_commander.listen();
+            } else {
+                self.respeaker_commander.off();
+            }
+            self.was_speaking = speaking;
         }
+        speaking
+    }
+
+    fn send_event(&mut self, event: AudioDetectorData) -> anyhow::Result<()> {
+        self.event_outbox.send(event)?;
         Ok(())
     }
 
@@ -167,121 +426,271 @@ impl Listener {
             let ts_now = chrono::Utc::now();
             let instant_now = Instant::now();
             let audio_frame = self.recorder.read().context("Failed to read audio frame")?;
+            let frame_started = Instant::now();
+            self.process_frame(&audio_frame, ts_now, instant_now, frame_started)?;
 
-            self.wake_word_validator.insert(instant_now, &audio_frame);
-
-            // skip in privacy mode
-            if self.check_privacy_mode()? {
-                self.respeaker_commander.off();
-                continue;
+            self.record_stage_timing(STAGE_FRAME, frame_started);
+            if let Some(stage_timings) = self
+                .stage_timer
+                .as_mut()
+                .and_then(|stage_timer| stage_timer.flush(Instant::now()))
+            {
+                self.send_event(AudioDetectorData::StageTimings(stage_timings))?;
             }
+        }
 
-            // check if validation future is resolved
-            // we don't need the resulting from this method right now
-            _ = self.check_wake_word_validation();
+        // TODO(David): Is this object RAII?
+        // Maybe we should have some nicer termination detection
+        //recorder.stop().context("Failed to stop audio recording")?;
+    }
 
-            // wake word detection
-            let detected_wake_word = self.detect_wake_word(&audio_frame)?;
-            if let Some(detected_wake_word) = detected_wake_word {
-                // detect dismiss keywords
-                if self.check_dismiss_keyword(&detected_wake_word, ts_now)? {
-                    self.respeaker_commander.off();
-                    continue;
-                }
+    /// Everything done with a single audio frame
+    ///
+    /// Returns early for skipped frames so that their processing time is still recorded
+    fn process_frame(
+        &mut self,
+        audio_frame: &[i16],
+        ts_now: chrono::DateTime<chrono::Utc>,
+        instant_now: Instant,
+        frame_started: Instant,
+    ) -> anyhow::Result<()> {
+        // retry messages held back while consumers were busy
+        self.audio_sample_outbox.flush()?;
+        self.event_outbox.flush()?;
+
+        self.wake_word_validator.insert(instant_now, audio_frame);
+        self.record_stage_timing(STAGE_VALIDATION_BUFFER, frame_started);
+
+        // skip in privacy mode
+        if self.check_privacy_mode()? {
+            self.respeaker_commander.off();
+            return Ok(());
+        }
+
+        // check if validation future is resolved
+        // we don't need the resulting from this method right now
+        _ = self.check_wake_word_validation();
+
+        self.check_follow_up_request(instant_now);
+
+        let speaking = self.check_speaking(instant_now);
+        if let Some(follow_up_window) = &mut self.follow_up_window {
+            follow_up_window.update(speaking, instant_now);
+        }
 
-                // don't update wake word if we're already recording
-                if !self.recording_status.active() {
-                    // starting new wakeword detection
-                    let validation_future = self
-                        .wake_word_validator
-                        .contains_wakeword(&detected_wake_word)?;
-                    self.wake_word_validation_future = Some(validation_future);
-                    self.respeaker_commander.listen();
-                    let active_recording = ActiveRecording::new(ts_now, detected_wake_word.clone());
-
-                    self.recording_status = RecordingStatus::Active(active_recording);
-
-                    // only send event when we start recording
-                    let event = AudioDetectorData::RecordingStarted(WakeWordDetection::new(
-                        detected_wake_word.clone(),
-                        ts_now,
-                    ));
-                    self.send_event(event)?;
+        // wake word detection
+        let started = Instant::now();
+        let detected_wake_word = self.detect_wake_word(audio_frame, speaking)?;
+        self.record_stage_timing(STAGE_PORCUPINE, started);
+        // counted before roles are applied so interrupt and command keywords are included
+        if let Some(wake_word) = &detected_wake_word {
+            self.metrics
+                .increment(&DETECTIONS, &[("keyword", wake_word.as_str())]);
+        }
+        // interrupt and command keywords never start or affect recordings
+        let detected_wake_word = match detected_wake_word {
+            Some(wake_word) => match self.keyword_role(&wake_word) {
+                KeywordRole::WakeWord => Some(wake_word),
+                KeywordRole::Interrupt => {
+                    self.send_stop_command(wake_word, ts_now)?;
+                    None
                 }
+                KeywordRole::Command => {
+                    self.send_keyword_command(&wake_word, ts_now)?;
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(detected_wake_word) = detected_wake_word {
+            // detect dismiss keywords
+            if self.check_dismiss_keyword(&detected_wake_word, ts_now)? {
+                self.respeaker_commander.off();
+                return Ok(());
+            }
 
-                // also bump this to prevent going to sleep if human detection is slow
-                self.last_human_speech_detected = instant_now;
+            // don't update wake word if we're already recording
+            if !self.recording_status.active() {
+                // wake word takes precedence over follow up
+                self.follow_up_window = None;
+                // starting new wakeword detection
+                self.start_wake_word_validation(&detected_wake_word, instant_now);
+                self.respeaker_commander.listen();
+                let direction_of_arrival = self.respeaker_commander.read_direction();
+                let mut active_recording =
+                    ActiveRecording::new(ts_now, detected_wake_word.clone(), direction_of_arrival);
+                if self.dataset_recorder.is_some() {
+                    active_recording.detection_window = self.wake_word_validator.window();
+                }
+                let recording_id = active_recording.recording_id;
 
-                tracing::info!("Detected {:?}", detected_wake_word);
+                self.recording_status = RecordingStatus::Active(active_recording);
 
-                let event = AudioDetectorData::WakeWordDetected(WakeWordDetection::new(
+                // only send event when we start recording
+                let event = AudioDetectorData::RecordingStarted(WakeWordDetection::new(
                     detected_wake_word.clone(),
                     ts_now,
+                    Some(recording_id),
                 ));
                 self.send_event(event)?;
             }
 
-            self.check_human_voice_probability(&audio_frame, ts_now)?;
+            // also bump this to prevent going to sleep if human detection is slow
+            self.last_human_speech_detected = instant_now;
 
-            // Add sample to buffer
-            if self.recording_status.active() {
-                self.audio_buffer.extend_from_slice(&audio_frame);
-            }
+            tracing::info!("Detected {:?}", detected_wake_word);
+
+            let event = AudioDetectorData::WakeWordDetected(WakeWordDetection::new(
+                detected_wake_word.clone(),
+                ts_now,
+                self.recording_status.recording_id(),
+            ));
+            self.send_event(event)?;
+        }
 
-            // Check timeout
-            let mut should_be_recording =
-                self.last_human_speech_detected.elapsed() < HUMAN_SPEECH_DETECTION_TIMEOUT;
+        let human_speech_detected = self.check_human_voice_probability(audio_frame, ts_now)?;
+        // robot's own voice shouldn't start follow up recordings
+        if human_speech_detected && !speaking && !self.recording_status.active() {
+            self.check_follow_up_start(ts_now, instant_now)?;
+        }
 
-            if let Some(recording_initial_status) = self
-                .recording_status
-                .is_in_recording_initial_timeout(ts_now)
-            {
-                // if we are in initial 3 seconds do not time out
-                if recording_initial_status {
-                    should_be_recording = true;
-                }
+        // Add sample to buffer
+        if self.recording_status.active() {
+            self.audio_buffer.extend_from_slice(audio_frame);
+        }
+
+        // Check timeout
+        let mut should_be_recording =
+            self.last_human_speech_detected.elapsed() < HUMAN_SPEECH_DETECTION_TIMEOUT;
+
+        if let Some(recording_initial_status) = self
+            .recording_status
+            .is_in_recording_initial_timeout(ts_now)
+        {
+            // if we are in initial 3 seconds do not time out
+            if recording_initial_status {
+                should_be_recording = true;
             }
+        }
 
-            if self.recording_status.active() && !should_be_recording {
-                // loop until validation is finished
-                loop {
-                    let validation_status = self.check_wake_word_validation()?;
-                    match validation_status {
-                        ValidationStatus::Processing => {
-                            // keep trying
-                            continue;
-                        }
-                        ValidationStatus::NotAvailable
-                        | ValidationStatus::Valid
-                        | ValidationStatus::NotValid => {
-                            // finished
-                            // we don't care how it finished
-                            // because the method will clean up in case the validation failed
-                            break;
-                        }
-                    }
+        if self.recording_status.active() && !should_be_recording {
+            // keep recording until validation is finished or misses its deadline
+            // the validation method cleans up if validation failed
+            match self.check_wake_word_validation()? {
+                ValidationStatus::Processing => (),
+                ValidationStatus::NotAvailable
+                | ValidationStatus::Valid
+                | ValidationStatus::NotValid => {
+                    // finish if validation didn't already cancel the recording
+                    self.finish_recording()?;
+                    self.respeaker_commander.off();
                 }
-                // stop recording
-                self.finish_recording()?;
-                self.respeaker_commander.off();
             }
         }
+        Ok(())
+    }
 
-        // TODO(David): Is this object RAII?
-        // Maybe we should have some nicer termination detection
-        //recorder.stop().context("Failed to stop audio recording")?;
+    /// Arm follow up window if downstream consumer requested it
+    fn check_follow_up_request(&mut self, instant_now: Instant) {
+        let request = match self.follow_up_request.lock() {
+            Ok(mut request) => request.take(),
+            Err(_) => {
+                warn!("Follow up request lock poisoned");
+                None
+            }
+        };
+        let Some(request) = request else {
+            return;
+        };
+        let last_finished_recording = match self.last_finished_recording.lock() {
+            Ok(last_finished_recording) => last_finished_recording.clone(),
+            Err(_) => {
+                warn!("Last finished recording lock poisoned");
+                None
+            }
+        };
+        let Some(last_finished_recording) = last_finished_recording else {
+            warn!("Follow up requested but no recording finished yet");
+            return;
+        };
+        if request
+            .recording_id
+            .is_some_and(|recording_id| recording_id != last_finished_recording.recording_id)
+        {
+            warn!(
+                "Follow up requested for {:?} which is not the last recording",
+                request.recording_id
+            );
+            return;
+        }
+        info!(
+            "Expecting follow up for {:?} within {}ms",
+            last_finished_recording.wake_word, request.timeout_ms
+        );
+        self.follow_up_window = Some(FollowUpWindow::new(
+            last_finished_recording.wake_word,
+            last_finished_recording.recording_id,
+            Duration::from_millis(request.timeout_ms),
+        ));
+    }
+
+    /// Start follow up recording if human speech was detected during follow up window
+    fn check_follow_up_start(
+        &mut self,
+        ts_now: chrono::DateTime<chrono::Utc>,
+        instant_now: Instant,
+    ) -> anyhow::Result<()> {
+        let Some(follow_up_window) = self.follow_up_window.take() else {
+            return Ok(());
+        };
+        if follow_up_window.expired(instant_now) {
+            info!("Follow up window expired");
+            return Ok(());
+        }
+
+        info!(
+            "Starting follow up recording for {:?}",
+            follow_up_window.wake_word
+        );
+        // no wake word was spoken so there is nothing to validate
+        self.wake_word_validation_future = None;
+        self.respeaker_commander.listen();
+        let direction_of_arrival = self.respeaker_commander.read_direction();
+        let active_recording = ActiveRecording::new(
+            ts_now,
+            follow_up_window.wake_word.clone(),
+            direction_of_arrival,
+        );
+        let event = AudioDetectorData::FollowUpRecordingStarted(FollowUpRecordingStarted::new(
+            follow_up_window.wake_word,
+            ts_now,
+            active_recording.recording_id,
+            follow_up_window.previous_recording_id,
+        ));
+        self.recording_status = RecordingStatus::Active(active_recording);
+        // speech started a few frames before voice probability crossed the threshold
+        self.audio_buffer = self
+            .wake_word_validator
+            .audio_before(instant_now, FOLLOW_UP_PRE_ROLL);
+        self.send_event(event)?;
+        Ok(())
     }
 
     fn check_privacy_mode(&mut self) -> anyhow::Result<bool> {
         // skip in privacy mode
         if self.privacy_mode_flag.load(Ordering::Relaxed) {
+            self.follow_up_window = None;
             // cancel recording if ongoing
-            if let RecordingStatus::Active(recording_status) = self.recording_status.stop() {
+            if let RecordingStatus::Active(recording_status) = self
+                .recording_status
+                .cancel(&mut self.wake_word_validation_future)
+            {
                 info!("Canceling recording because of privacy mode");
                 let event = AudioDetectorData::RecordingEnd(WakeWordDetectionEnd::new(
                     recording_status.recording_triggering_wake_word,
                     recording_status.recording_triggering_timestamp,
                     DetectionEndReason::PrivacyModeActivated,
+                    recording_status.recording_id,
                 ));
                 self.send_event(event)?;
             }
@@ -299,19 +708,32 @@ impl Listener {
         ts_now: chrono::DateTime<chrono::Utc>,
     ) -> anyhow::Result<bool> {
         if self
-            .dismiss_keyword
-            .as_ref()
-            .is_some_and(|dismiss_keyword| dismiss_keyword == detected_wake_word)
+            .dismiss_keywords
+            .iter()
+            .any(|dismiss_keyword| dismiss_keyword == detected_wake_word)
         {
-            info!("Dismiss keyword detected {:?}", self.dismiss_keyword);
+            info!("Dismiss keyword detected {:?}", detected_wake_word);
+            self.follow_up_window = None;
+            // detection event correlates with the recording it dismissed
+            let dismissed_recording_id = self.recording_status.recording_id();
             // cancel recording if ongoing
-            if let RecordingStatus::Active(recording_status) = self.recording_status.stop() {
+            if let RecordingStatus::Active(recording_status) = self
+                .recording_status
+                .cancel(&mut self.wake_word_validation_future)
+            {
                 info!("Canceling recording because of dismiss keyword");
-                let event = AudioDetectorData::RecordingEnd(WakeWordDetectionEnd::new(
-                    recording_status.recording_triggering_wake_word,
-                    recording_status.recording_triggering_timestamp,
-                    DetectionEndReason::Dismissed,
-                ));
+                self.save_to_dataset(&recording_status, DatasetLabel::Dismissed);
+                let event = AudioDetectorData::RecordingEnd(
+                    WakeWordDetectionEnd::new(
+                        recording_status.recording_triggering_wake_word,
+                        recording_status.recording_triggering_timestamp,
+                        DetectionEndReason::Dismissed,
+                        recording_status.recording_id,
+                    )
+                    .with_dismiss_rule(DismissRule::Keyword {
+                        keyword: detected_wake_word.to_owned(),
+                    }),
+                );
                 self.send_event(event)?;
             }
             // clear after recording
@@ -320,6 +742,7 @@ impl Listener {
             let event = AudioDetectorData::WakeWordDetected(WakeWordDetection::new(
                 detected_wake_word.to_owned(),
                 ts_now,
+                dismissed_recording_id,
             ));
             self.send_event(event)?;
             Ok(true)
@@ -328,71 +751,170 @@ impl Listener {
         }
     }
 
+    fn start_wake_word_validation(&mut self, wake_word: &str, instant_now: Instant) {
+        if self.validation_failure_policy == ValidationFailurePolicy::Skip {
+            self.wake_word_validation_future = None;
+            return;
+        }
+        let keyword_settings = self
+            .keyword_settings
+            .get(wake_word)
+            .cloned()
+            .unwrap_or_default();
+        if !keyword_settings.validate {
+            info!("Validation disabled for {:?}", wake_word);
+            self.wake_word_validation_future = None;
+            return;
+        }
+        let receiver = match self.wake_word_validator.contains_wakeword(
+            wake_word,
+            keyword_settings.transcription_language(),
+            keyword_settings.spellings(wake_word),
+            keyword_settings
+                .validation_similarity_threshold
+                .unwrap_or(self.validation_similarity_threshold),
+        ) {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                // resolve as failure so the failure policy applies
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                _ = tx.send(Err(err));
+                rx
+            }
+        };
+        self.wake_word_validation_future = Some(PendingValidation {
+            wake_word: wake_word.to_owned(),
+            receiver,
+            started: instant_now,
+            deadline: instant_now + self.validation_deadline,
+        });
+    }
+
     fn check_wake_word_validation(&mut self) -> anyhow::Result<ValidationStatus> {
-        if let Some(future) = &mut self.wake_word_validation_future {
-            match future.try_recv() {
-                Ok(validated) => {
-                    if validated {
-                        info!("Wakeword validated successfully");
-                        self.wake_word_validation_future = None;
-                        Ok(ValidationStatus::Valid)
-                    } else {
-                        warn!("Wakeword not detected during validation. Stopping recording");
-                        if let RecordingStatus::Active(recording_status) =
-                            self.recording_status.stop()
-                        {
-                            info!("Canceling recording because of failing validation keyword");
-                            let event = AudioDetectorData::RecordingEnd(WakeWordDetectionEnd::new(
-                                recording_status.recording_triggering_wake_word,
-                                recording_status.recording_triggering_timestamp,
-                                DetectionEndReason::Dismissed,
-                            ));
-                            // clear after recording
-                            self.send_event(event)?;
-                        }
-                        // clear buffer
-                        self.audio_buffer.clear();
-                        self.wake_word_validation_future = None;
-                        Ok(ValidationStatus::NotValid)
-                    }
-                }
-                Err(TryRecvError::Empty) => {
-                    // keep waiting
-                    Ok(ValidationStatus::Processing)
-                }
-                Err(TryRecvError::Closed) => {
-                    self.wake_word_validation_future = None;
-                    anyhow::bail!("failed to validate wakeword")
+        let Some(pending_validation) = &mut self.wake_word_validation_future else {
+            return Ok(ValidationStatus::NotAvailable);
+        };
+        let outcome = match pending_validation.receiver.try_recv() {
+            Ok(outcome) => outcome,
+            Err(TryRecvError::Empty) if Instant::now() < pending_validation.deadline => {
+                // keep waiting
+                return Ok(ValidationStatus::Processing);
+            }
+            Err(TryRecvError::Empty) => Err(anyhow::anyhow!("Validation missed its deadline")),
+            Err(TryRecvError::Closed) => Err(anyhow::anyhow!("Validation dropped without result")),
+        };
+        let accept_failure = self.validation_failure_policy == ValidationFailurePolicy::Accept;
+        let mut validation_result = ValidationResult {
+            wake_word: pending_validation.wake_word.clone(),
+            recording_id: self.recording_status.recording_id(),
+            transcript: None,
+            score: None,
+            latency_ms: pending_validation.started.elapsed().as_millis() as u64,
+            decision: ValidationDecision::Valid,
+            error: None,
+        };
+        let validated = match outcome {
+            Ok(outcome) => {
+                validation_result.transcript = outcome.transcript;
+                validation_result.score = Some(outcome.score);
+                if !outcome.valid {
+                    validation_result.decision = ValidationDecision::NotValid;
                 }
+                outcome.valid
             }
-        } else {
-            Ok(ValidationStatus::NotAvailable)
+            Err(err) => {
+                warn!("Wakeword validation failed {:?}", err);
+                validation_result.error = Some(err.to_string());
+                validation_result.decision = if accept_failure {
+                    ValidationDecision::FailedAccepted
+                } else {
+                    ValidationDecision::FailedRejected
+                };
+                accept_failure
+            }
+        };
+        self.wake_word_validation_future = None;
+        self.send_event(AudioDetectorData::ValidationResult(validation_result))?;
+
+        if validated {
+            info!("Wakeword validated successfully");
+            return Ok(ValidationStatus::Valid);
+        }
+
+        warn!("Wakeword not validated. Stopping recording");
+        if let RecordingStatus::Active(recording_status) = self.recording_status.stop() {
+            info!("Canceling recording because of failing validation keyword");
+            self.save_to_dataset(&recording_status, DatasetLabel::Rejected);
+            let event = AudioDetectorData::RecordingEnd(WakeWordDetectionEnd::new(
+                recording_status.recording_triggering_wake_word,
+                recording_status.recording_triggering_timestamp,
+                DetectionEndReason::ValidationFailed,
+                recording_status.recording_id,
+            ));
+            self.send_event(event)?;
+            self.respeaker_commander.off();
+        }
+        // clear buffer
+        self.audio_buffer.clear();
+        Ok(ValidationStatus::NotValid)
+    }
+
+    fn record_stage_timing(&mut self, stage: &str, started: Instant) {
+        if let Some(stage_timer) = &mut self.stage_timer {
+            stage_timer.record(stage, started.elapsed());
         }
     }
 
+    /// Queue audio window around the detection that started the recording
+    fn save_to_dataset(&self, recording: &ActiveRecording, label: DatasetLabel) {
+        let Some(dataset_recorder) = &self.dataset_recorder else {
+            return;
+        };
+        // follow up recordings don't have a detection
+        if recording.detection_window.is_empty() {
+            return;
+        }
+        dataset_recorder.save(DatasetSample {
+            recording_id: recording.recording_id,
+            wake_word: recording.recording_triggering_wake_word.clone(),
+            label,
+            samples: recording.detection_window.clone(),
+            sample_rate: self.porcupine.sample_rate(),
+            timestamp: recording.recording_triggering_timestamp,
+        });
+    }
+
     fn check_human_voice_probability(
         &mut self,
         audio_frame: &[i16],
         ts_now: chrono::DateTime<chrono::Utc>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         // voice probability
+        let started = Instant::now();
         let voice_probability = self
             .cobra
             .process(audio_frame)
             .map_err(WakewordError::CobraError)
             .context("Cobra processing failed")?;
+        self.record_stage_timing(STAGE_COBRA, started);
 
         let time_since_last_human_speech_detected_ms =
             self.last_human_speech_detected.elapsed().as_millis();
 
         // send event
-        let event = AudioDetectorData::VoiceProbability(VoiceProbability::new(
+        if let Some(published_probability) = self.voice_probability_aggregator.push(
             voice_probability,
-            ts_now,
-            time_since_last_human_speech_detected_ms as u64,
+            Instant::now(),
             self.recording_status.active(),
-        ));
-        self.send_event(event)?;
+        ) {
+            let event = AudioDetectorData::VoiceProbability(VoiceProbability::new(
+                published_probability,
+                ts_now,
+                time_since_last_human_speech_detected_ms as u64,
+                self.recording_status.recording_id(),
+            ));
+            self.send_event(event)?;
+        }
 
         // Check human speech presence
         let human_speech_detected =
@@ -400,32 +922,69 @@ impl Listener {
         if human_speech_detected {
             self.last_human_speech_detected = Instant::now();
         }
-        Ok(())
+        Ok(human_speech_detected)
     }
 
     /// Finish recording and send data
     fn finish_recording(&mut self) -> anyhow::Result<()> {
         if let RecordingStatus::Active(recording_status) = self.recording_status.stop() {
             let audio_sample = AudioSample {
+                recording_id: recording_status.recording_id,
                 data: self.audio_buffer.clone(),
                 wake_word: recording_status.recording_triggering_wake_word.clone(),
                 sample_rate: self.porcupine.sample_rate(),
                 timestamp: recording_status.recording_triggering_timestamp,
+                end_timestamp: chrono::Utc::now(),
+                direction_of_arrival: recording_status.direction_of_arrival,
+                detection_window: recording_status.detection_window,
             };
             // erase audio buffer after sending
             self.audio_buffer.clear();
 
             tracing::info!("Sending audio sample");
-            if let Err(TrySendError::Closed(_)) = self.audio_sample_sender.try_send(audio_sample) {
-                anyhow::bail!("Audio sample channel closed");
+            let queue_saturated = TranscriptionQueueSaturated::new(
+                &audio_sample,
+                self.audio_sample_outbox.pending() + 1,
+                self.audio_sample_outbox.capacity(),
+            );
+            if self.audio_sample_outbox.send(audio_sample)? == SendOutcome::HeldBack {
+                warn!(
+                    "Transcription queue full. Holding back {} recordings",
+                    self.audio_sample_outbox.pending()
+                );
+                self.send_event(AudioDetectorData::TranscriptionQueueSaturated(
+                    queue_saturated,
+                ))?;
             }
 
             let event = AudioDetectorData::RecordingEnd(WakeWordDetectionEnd::new(
                 recording_status.recording_triggering_wake_word.clone(),
                 recording_status.recording_triggering_timestamp,
                 DetectionEndReason::Finished,
+                recording_status.recording_id,
             ));
             self.send_event(event)?;
+
+            if let Some(follow_up_window_ms) = self
+                .keyword_settings
+                .get(&recording_status.recording_triggering_wake_word)
+                .and_then(|settings| settings.follow_up_window_ms)
+            {
+                self.follow_up_window = Some(FollowUpWindow::new(
+                    recording_status.recording_triggering_wake_word.clone(),
+                    recording_status.recording_id,
+                    Duration::from_millis(follow_up_window_ms),
+                ));
+            }
+            match self.last_finished_recording.lock() {
+                Ok(mut last_finished_recording) => {
+                    *last_finished_recording = Some(FinishedRecording {
+                        recording_id: recording_status.recording_id,
+                        wake_word: recording_status.recording_triggering_wake_word,
+                    })
+                }
+                Err(_) => warn!("Last finished recording lock poisoned"),
+            }
         }
         Ok(())
     }
@@ -441,12 +1000,27 @@ impl RecordingStatus {
         matches!(self, RecordingStatus::Active(_))
     }
 
+    fn recording_id(&self) -> Option<Uuid> {
+        match self {
+            RecordingStatus::Active(status) => Some(status.recording_id),
+            RecordingStatus::NotActive => None,
+        }
+    }
+
     fn stop(&mut self) -> RecordingStatus {
         let mut tmp = RecordingStatus::NotActive;
         std::mem::swap(self, &mut tmp);
         tmp
     }
 
+    /// Stop recording and drop validation of the wake word that started it
+    ///
+    /// A validation resolving later would otherwise be reported without a recording
+    fn cancel(&mut self, pending_validation: &mut Option<PendingValidation>) -> RecordingStatus {
+        *pending_validation = None;
+        self.stop()
+    }
+
     fn is_in_recording_initial_timeout(
         &self,
         ts_now: chrono::DateTime<chrono::Utc>,
@@ -466,18 +1040,154 @@ impl RecordingStatus {
 }
 
 pub struct ActiveRecording {
+    /// Unique ID used to correlate all events and audio belonging to this recording
+    recording_id: Uuid,
     recording_triggering_timestamp: chrono::DateTime<chrono::Utc>,
     recording_triggering_wake_word: String,
+    direction_of_arrival: Option<i32>,
+    /// Validation audio window captured at detection for the dataset
+    detection_window: Vec<i16>,
 }
 
 impl ActiveRecording {
     fn new(
         recording_triggering_timestamp: chrono::DateTime<chrono::Utc>,
         recording_triggering_wake_word: String,
+        direction_of_arrival: Option<i32>,
     ) -> Self {
         Self {
+            recording_id: Uuid::new_v4(),
             recording_triggering_timestamp,
             recording_triggering_wake_word,
+            direction_of_arrival,
+            detection_window: vec![],
         }
     }
 }
+
+#[derive(Clone)]
+pub struct FinishedRecording {
+    recording_id: Uuid,
+    wake_word: String,
+}
+
+struct FollowUpWindow {
+    wake_word: String,
+    previous_recording_id: Uuid,
+    duration: Duration,
+    /// Not set while the robot is speaking so that the window starts after its reply
+    deadline: Option<Instant>,
+}
+
+impl FollowUpWindow {
+    fn new(wake_word: String, previous_recording_id: Uuid, duration: Duration) -> Self {
+        Self {
+            wake_word,
+            previous_recording_id,
+            duration,
+            deadline: None,
+        }
+    }
+
+    /// Restart window each time the robot stops speaking
+    fn update(&mut self, speaking: bool, instant_now: Instant) {
+        if speaking {
+            self.deadline = None;
+        } else if self.deadline.is_none() {
+            self.deadline = Some(instant_now + self.duration);
+        }
+    }
+
+    fn expired(&self, instant_now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| instant_now > deadline)
+    }
+}
+
+/// Validation of the wake word that started the active recording
+struct PendingValidation {
+    wake_word: String,
+    receiver: ValidationReceiver,
+    started: Instant,
+    deadline: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaking_flag_expires() {
+        let flag = SpeakingFlag::default();
+        let now = flag.reference;
+        let timeout = Duration::from_secs(30);
+        assert!(!flag.is_speaking_at(now));
+
+        flag.set_at(now, true, timeout);
+        assert!(flag.is_speaking_at(now + timeout - Duration::from_millis(1)));
+        // TTS node never reported that it stopped speaking
+        assert!(!flag.is_speaking_at(now + timeout));
+
+        // refreshing extends speaking
+        flag.set_at(now + timeout, true, timeout);
+        assert!(flag.is_speaking_at(now + timeout));
+        flag.set_at(now + timeout, false, timeout);
+        assert!(!flag.is_speaking_at(now + timeout));
+    }
+
+    const WINDOW: Duration = Duration::from_millis(5000);
+
+    #[test]
+    fn cancelling_recording_drops_pending_validation() {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let now = Instant::now();
+        let mut pending_validation = Some(PendingValidation {
+            wake_word: String::from("hey hopper"),
+            receiver,
+            started: now,
+            deadline: now + WINDOW,
+        });
+        let mut recording_status = RecordingStatus::Active(ActiveRecording::new(
+            chrono::Utc::now(),
+            String::from("hey hopper"),
+            None,
+        ));
+
+        // dismiss keyword spoken before validation resolved
+        let cancelled = recording_status.cancel(&mut pending_validation);
+        assert!(cancelled.active());
+        assert!(!recording_status.active());
+        assert!(pending_validation.is_none());
+        // late result has nowhere to go instead of counting without a recording
+        assert!(sender.is_closed());
+    }
+
+    #[test]
+    fn follow_up_window_waits_for_robot_to_stop_speaking() {
+        let start = Instant::now();
+        let mut window = FollowUpWindow::new(String::from("Hey Hopper"), Uuid::nil(), WINDOW);
+        window.update(true, start);
+        // robot speaking for longer than the window
+        window.update(true, start + WINDOW * 3);
+        assert!(!window.expired(start + WINDOW * 3));
+
+        let speaking_ended = start + WINDOW * 3;
+        window.update(false, speaking_ended);
+        window.update(false, speaking_ended + WINDOW);
+        assert!(!window.expired(speaking_ended + WINDOW));
+        assert!(window.expired(speaking_ended + WINDOW + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn follow_up_window_restarts_after_each_reply() {
+        let start = Instant::now();
+        let mut window = FollowUpWindow::new(String::from("Hey Hopper"), Uuid::nil(), WINDOW);
+        window.update(false, start);
+        // robot starts another sentence just before the window runs out
+        let second_reply = start + WINDOW - Duration::from_millis(100);
+        window.update(true, second_reply);
+        let second_reply_ended = second_reply + Duration::from_secs(2);
+        window.update(false, second_reply_ended);
+        assert!(!window.expired(start + WINDOW * 2));
+        assert!(window.expired(second_reply_ended + WINDOW + Duration::from_millis(1)));
+    }
+}
@@ -1,8 +1,12 @@
 use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc::error::TrySendError;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use zenoh::{prelude::r#async::*, Session as ZenohSession};
 
-use crate::WakewordError;
+use crate::{
+    metrics::{Metrics, MESSAGES_DROPPED},
+    WakewordError,
+};
 
 const TRACING_ZENOH_TOPIC_FULL: &str = "/tracing/full";
 const TRACING_ZENOH_TOPIC_JSON: &str = "/tracing/json";
@@ -14,7 +18,7 @@ pub fn set_global_tracing_zenoh_subscriber(session: Arc<ZenohSession>) {
     GLOBAL_ZENOH_SESSION.set(session).unwrap();
 }
 
-pub fn setup_tracing(verbosity_level: u8, topic_prefix: &str) {
+pub fn setup_tracing(verbosity_level: u8, topic_prefix: &str, metrics: Arc<Metrics>) {
     let filter = match verbosity_level {
         0 => tracing::level_filters::LevelFilter::INFO,
         1 => tracing::level_filters::LevelFilter::DEBUG,
@@ -23,7 +27,8 @@ pub fn setup_tracing(verbosity_level: u8, topic_prefix: &str) {
     };
 
     let tracing_zenoh_topic_full = format!("{}{}", topic_prefix, TRACING_ZENOH_TOPIC_FULL);
-    let zenoh_full_writer = start_log_writer(&tracing_zenoh_topic_full);
+    let zenoh_full_writer =
+        start_log_writer(&tracing_zenoh_topic_full, "log_full", metrics.clone());
     let zenoh_full_layer = fmt::Layer::default()
         .with_thread_names(true)
         .with_thread_ids(true)
@@ -32,7 +37,7 @@ pub fn setup_tracing(verbosity_level: u8, topic_prefix: &str) {
         .with_writer(move || zenoh_full_writer.clone());
 
     let tracing_zenoh_topic_json = format!("{}{}", topic_prefix, TRACING_ZENOH_TOPIC_JSON);
-    let zenoh_json_writer = start_log_writer(&tracing_zenoh_topic_json);
+    let zenoh_json_writer = start_log_writer(&tracing_zenoh_topic_json, "log_json", metrics);
     let zenoh_json_layer = fmt::Layer::default()
         .json()
         .with_thread_names(true)
@@ -57,13 +62,21 @@ pub fn setup_tracing(verbosity_level: u8, topic_prefix: &str) {
 #[derive(Clone)]
 struct LogWriter {
     sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+    /// Channel name used when counting dropped lines
+    channel: &'static str,
+    metrics: Arc<Metrics>,
 }
 
 impl std::io::Write for LogWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let data = buf.to_vec();
-        // ignore errors
-        _ = self.sender.try_send(data);
+        // logging about dropped log lines would only add to the pile so just count them
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(data) {
+            self.metrics.increment(
+                &MESSAGES_DROPPED,
+                &[("channel", self.channel), ("kind", "log_line")],
+            );
+        }
         Ok(buf.len())
     }
 
@@ -72,9 +85,13 @@ impl std::io::Write for LogWriter {
     }
 }
 
-fn start_log_writer(topic: &str) -> LogWriter {
+fn start_log_writer(topic: &str, channel: &'static str, metrics: Arc<Metrics>) -> LogWriter {
     let (sender, receiver) = tokio::sync::mpsc::channel(100);
-    let log_writer = LogWriter { sender };
+    let log_writer = LogWriter {
+        sender,
+        channel,
+        metrics,
+    };
 
     tokio::spawn({
         let topic = topic.to_owned();
@@ -4,43 +4,78 @@
 //! and [cobra](https://github.com/Picovoice/cobra/blob/main/demo/rust/micdemo/src/main.rs)
 //! By the excellent folks at https://picovoice.ai/
 
+mod audio_encoding;
+mod backpressure;
+mod bench;
+mod calibration;
 mod configuration;
+mod dataset;
+mod intent_parser;
 mod listener;
 mod logging;
 mod messages;
+mod metrics;
 mod respeaker;
+mod stage_timing;
+mod system_prompt;
+mod template_validation;
+mod transcript_filter;
+mod transcript_router;
+mod transcription;
+mod voice_telemetry;
 mod wakeword_validation;
 
-use async_openai::{
-    config::OpenAIConfig,
-    types::{AudioInput, CreateTranscriptionRequestArgs},
-    Client as OpenAiClient,
-};
-use clap::Parser;
+use anyhow::Context;
+use async_openai::{config::OpenAIConfig, Client as OpenAiClient};
+use clap::{Parser, Subcommand};
 
 use pv_recorder::PvRecorderBuilder;
 use std::{
+    collections::HashMap,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
 use thiserror::Error;
 use tracing::{info, warn};
-use zenoh::{prelude::r#async::*, publication::Publisher};
+use zenoh::prelude::r#async::*;
 
 use configuration::{get_configuration, AppConfig, PicovoiceConfig};
-use listener::{AudioDetectorData, Listener};
+use dataset::{DatasetLabel, DatasetRecorder};
+use intent_parser::IntentParser;
+use listener::{AudioDetectorData, Listener, ListenerSharedState, SpeakingFlag};
 use logging::{set_global_tracing_zenoh_subscriber, setup_tracing};
-use messages::{AudioSample, AudioTranscript, PrivacyModeCommand, VoiceProbability};
+use messages::{
+    AudioSample, AudioTranscript, ExpectFollowUpCommand, IntentDetected, PrivacyModeCommand,
+    PromptContextCommand, RoutedTranscript, SpeakingStatus, TranscriptDismissed,
+    TranscriptFilterReason, TranscriptFiltered, TranscriptionFailed, ValidationResult,
+    VoiceProbability, WakewordStatus,
+};
+use metrics::{
+    enum_label, Metrics, DISMISSALS, RECORDINGS, RECORDING_DURATION, TRANSCRIPTION_ERRORS,
+    TRANSCRIPTION_LATENCY, VALIDATIONS,
+};
 use respeaker::{start_respeaker_loop, ReSpeakerCommander};
+use stage_timing::LatencyHistogram;
+use system_prompt::{update_prompt_context, PromptContext, SystemPrompts};
+use transcript_filter::{DismissPhrases, TranscriptFilter};
+use transcript_router::TranscriptRouter;
+use transcription::{Transcriber, TranscriptionError};
+use wakeword_validation::build_validation_backend;
 
 const VOICE_TO_TEXT_TRANSCRIBE_MODEL: &str = "whisper-1";
 const VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE: &str = "en";
 const HUMAN_SPEECH_DETECTION_TIMEOUT: Duration = Duration::from_millis(1500);
 const RECORDING_INITIAL_TIMEOUT: chrono::TimeDelta = chrono::TimeDelta::milliseconds(4000);
 const HUMAN_SPEECH_DETECTION_PROBABILITY_THRESHOLD: f32 = 0.5;
+/// Delay before the listener is rebuilt after an error
+const LISTENER_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Audio before speech was detected that is added to the start of follow up recordings
+const FOLLOW_UP_PRE_ROLL: Duration = Duration::from_millis(500);
+/// Picovoice engines only accept 16 kHz audio
+const PICOVOICE_SAMPLE_RATE: u32 = 16000;
 
 /// Wake Word detection application using picovoice and zenoh
 #[derive(Parser)]
@@ -57,23 +92,99 @@ struct Args {
     /// Sets the level of verbosity
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Bundle captured false positive dataset with per keyword statistics
+    ExportDataset {
+        /// Directory the dataset is copied into
+        #[arg(long)]
+        output: std::path::PathBuf,
+    },
+    /// Sweep sensitivities over labelled recordings and recommend values
+    Calibrate {
+        /// Contains `<keyword>/*.wav` recordings and `negative/*.wav` background audio
+        directory: std::path::PathBuf,
+        /// Number of sensitivity steps between 0 and 1
+        #[arg(long, default_value_t = 10)]
+        steps: usize,
+        /// False alarm budget used for recommendations
+        #[arg(long, default_value_t = 1.0)]
+        max_false_alarms_per_hour: f32,
+        /// Write recommended sensitivities into the file passed with --config
+        #[arg(long)]
+        write_config: bool,
+    },
+    /// Measure per frame latency of the detection path
+    Bench {
+        /// Mono 16 bit WAV at porcupine sample rate. Synthetic noise is used if not provided
+        #[arg(long)]
+        wav: Option<std::path::PathBuf>,
+        /// Length of synthetic audio in seconds
+        #[arg(long, default_value_t = 60)]
+        seconds: u64,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Args = Args::parse();
-    setup_tracing(args.verbose, "wakeword");
+    let metrics = Arc::new(Metrics::default());
+    setup_tracing(args.verbose, "wakeword", metrics.clone());
 
     let app_config = get_configuration(&args.config)?;
+    // listener is rebuilt on errors so config mistakes have to fail here instead
+    app_config.picovoice.validate_keyword_settings()?;
+    app_config.validate_wake_word_filters()?;
 
     if args.show_audio_devices {
         show_audio_devices(&app_config.picovoice);
         return Ok(());
     }
 
+    if let Some(command) = &args.command {
+        return match command {
+            Command::ExportDataset { output } => {
+                export_dataset_command(&app_config.picovoice, output)
+            }
+            Command::Calibrate {
+                directory,
+                steps,
+                max_false_alarms_per_hour,
+                write_config,
+            } => {
+                let write_config = if *write_config {
+                    Some(
+                        args.config
+                            .as_deref()
+                            .context("--write-config requires --config")?,
+                    )
+                } else {
+                    None
+                };
+                calibrate_command(
+                    &app_config.picovoice,
+                    directory,
+                    *steps,
+                    *max_false_alarms_per_hour,
+                    write_config,
+                )
+            }
+            Command::Bench { wav, seconds } => bench_command(
+                &app_config.picovoice,
+                wav.as_deref(),
+                Duration::from_secs(*seconds),
+            ),
+        };
+    }
+
     let respeaker_commander = if app_config.app.enable_respeaker_integration {
         info!("ReSpeaker integration enabled");
-        start_respeaker_loop()
+        start_respeaker_loop(metrics.clone())
     } else {
         warn!("ReSpeaker integration disabled");
         ReSpeakerCommander::dummy()
@@ -88,34 +199,57 @@ async fn main() -> anyhow::Result<()> {
 
     set_global_tracing_zenoh_subscriber(zenoh_session.clone());
 
-    let (audio_sample_sender, mut audio_sample_receiver) = tokio::sync::mpsc::channel(100);
+    let (audio_sample_sender, mut audio_sample_receiver) =
+        tokio::sync::mpsc::channel(app_config.transcription.queue_size);
     let (audio_detector_event_sender, audio_detector_event_receiver) =
         tokio::sync::mpsc::channel(100);
 
-    let privacy_mode_flag = Arc::new(AtomicBool::new(false));
+    let listener_shared_state = ListenerSharedState {
+        privacy_mode_flag: Arc::new(AtomicBool::new(false)),
+        follow_up_request: Arc::new(Mutex::new(None)),
+        speaking_flag: Arc::new(SpeakingFlag::default()),
+        last_finished_recording: Arc::new(Mutex::new(None)),
+        metrics: metrics.clone(),
+        dataset_recorder: app_config
+            .picovoice
+            .dataset_capture
+            .as_ref()
+            .map(|config| DatasetRecorder::new(config, metrics.clone()))
+            .transpose()?,
+    };
+    let prompt_context = PromptContext::default();
 
     let openai_config = OpenAIConfig::new().with_api_key(&app_config.openai.api_key);
     let open_ai_client = OpenAiClient::with_config(openai_config);
 
+    let validation_backend = build_validation_backend(
+        &app_config.picovoice.validation_backend,
+        &app_config.picovoice.validated_keywords()?,
+        PICOVOICE_SAMPLE_RATE,
+        open_ai_client.clone(),
+    )
+    .context("Failed to build wake word validation backend")?;
+
     // start listener
     let _listener_loop_join_handle = tokio::task::spawn_blocking({
         let app_config = app_config.clone();
-        let privacy_mode_flag = privacy_mode_flag.clone();
+        let listener_shared_state = listener_shared_state.clone();
         let speaker_commander = respeaker_commander.clone();
-        let open_ai_client = open_ai_client.clone();
+        let audio_detector_event_sender = audio_detector_event_sender.clone();
 
         move || loop {
             let mut listener = match Listener::new(
                 app_config.picovoice.clone(),
                 audio_sample_sender.clone(),
                 audio_detector_event_sender.clone(),
-                privacy_mode_flag.clone(),
+                listener_shared_state.clone(),
                 speaker_commander.clone(),
-                open_ai_client.clone(),
+                validation_backend.clone(),
             ) {
                 Ok(listener) => listener,
                 Err(err) => {
                     tracing::error!("Error while creating listener {:?}", err);
+                    std::thread::sleep(LISTENER_RESTART_DELAY);
                     continue;
                 }
             };
@@ -125,6 +259,9 @@ async fn main() -> anyhow::Result<()> {
                     tracing::error!("Error in listener loop: {:?}", err);
                 }
             }
+            // release the recorder before opening it again
+            drop(listener);
+            std::thread::sleep(LISTENER_RESTART_DELAY);
         }
     });
 
@@ -134,6 +271,14 @@ async fn main() -> anyhow::Result<()> {
         .await
         .map_err(WakewordError::ZenohError)?;
 
+    let ListenerSharedState {
+        privacy_mode_flag,
+        follow_up_request,
+        speaking_flag,
+        dataset_recorder,
+        ..
+    } = listener_shared_state;
+
     tokio::spawn(async move {
         loop {
             let res: anyhow::Result<()> = async {
@@ -150,14 +295,174 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    if let Some(speaking_status_topic) = &app_config.app.speaking_status_topic {
+        let speaking_status_timeout = app_config.app.speaking_status_timeout();
+        let speaking_status_subscriber = zenoh_session
+            .declare_subscriber(speaking_status_topic.clone())
+            .res()
+            .await
+            .map_err(WakewordError::ZenohError)?;
+
+        tokio::spawn(async move {
+            loop {
+                let res: anyhow::Result<()> = async {
+                    let msg = speaking_status_subscriber.recv_async().await?;
+                    let msg: String = msg.value.try_into()?;
+                    let speaking_status: SpeakingStatus = serde_json::from_str(&msg)?;
+                    speaking_flag.set(speaking_status.speaking, speaking_status_timeout);
+                    Ok(())
+                }
+                .await;
+                if let Err(err) = res {
+                    tracing::error!("Error in speaking status subscriber: {:?}", err);
+                }
+            }
+        });
+    }
+
+    let expect_follow_up_subscriber = zenoh_session
+        .declare_subscriber(app_config.app.get_expect_follow_up_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    tokio::spawn(async move {
+        loop {
+            let res: anyhow::Result<()> = async {
+                let msg = expect_follow_up_subscriber.recv_async().await?;
+                let msg: String = msg.value.try_into()?;
+                let expect_follow_up: ExpectFollowUpCommand = serde_json::from_str(&msg)?;
+                *follow_up_request
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Follow up request lock poisoned"))? =
+                    Some(expect_follow_up);
+                Ok(())
+            }
+            .await;
+            if let Err(err) = res {
+                tracing::error!("Error in expect follow up subscriber: {:?}", err);
+            }
+        }
+    });
+
+    let prompt_context_subscriber = zenoh_session
+        .declare_subscriber(app_config.app.get_prompt_context_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    tokio::spawn({
+        let prompt_context = prompt_context.clone();
+        async move {
+            loop {
+                let res: anyhow::Result<()> = async {
+                    let msg = prompt_context_subscriber.recv_async().await?;
+                    let msg: String = msg.value.try_into()?;
+                    let prompt_context_command: PromptContextCommand = serde_json::from_str(&msg)?;
+                    update_prompt_context(&prompt_context, prompt_context_command);
+                    Ok(())
+                }
+                .await;
+                if let Err(err) = res {
+                    tracing::error!("Error in prompt context subscriber: {:?}", err);
+                }
+            }
+        }
+    });
+
+    let status = Arc::new(Mutex::new(WakewordStatus::default()));
+
+    let status_queryable = zenoh_session
+        .declare_queryable(app_config.app.get_status_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    tokio::spawn({
+        let status = status.clone();
+        async move {
+            loop {
+                let res: anyhow::Result<()> = async {
+                    let query = status_queryable.recv_async().await?;
+                    let status_json = {
+                        let status = status
+                            .lock()
+                            .map_err(|_| anyhow::anyhow!("Status lock poisoned"))?;
+                        serde_json::to_string(&*status)?
+                    };
+                    query
+                        .reply(Ok(Sample::new(query.key_expr().clone(), status_json)))
+                        .res()
+                        .await
+                        .map_err(WakewordError::ZenohError)?;
+                    Ok(())
+                }
+                .await;
+                if let Err(err) = res {
+                    tracing::error!("Error in status queryable: {:?}", err);
+                }
+            }
+        }
+    });
+
+    if let Some(http_address) = app_config.metrics.http_address {
+        tokio::spawn({
+            let metrics = metrics.clone();
+            async move {
+                if let Err(err) = metrics::serve_metrics(http_address, metrics).await {
+                    tracing::error!("Error in metrics server: {:?}", err);
+                }
+            }
+        });
+    }
+
+    if let Some(telemetry_interval) = app_config.metrics.telemetry_interval() {
+        let metrics_publisher = zenoh_session
+            .declare_publisher(app_config.app.get_metrics_topic())
+            .priority(Priority::InteractiveLow)
+            .congestion_control(CongestionControl::Drop)
+            .res()
+            .await
+            .map_err(WakewordError::ZenohError)?;
+
+        tokio::spawn({
+            let metrics = metrics.clone();
+            async move {
+                let mut interval = tokio::time::interval(telemetry_interval);
+                loop {
+                    interval.tick().await;
+                    let res: anyhow::Result<()> = async {
+                        metrics_publisher
+                            .put(serde_json::to_string(&metrics.snapshot())?)
+                            .res()
+                            .await
+                            .map_err(WakewordError::ZenohError)?;
+                        Ok(())
+                    }
+                    .await;
+                    if let Err(err) = res {
+                        tracing::error!("Error publishing metrics: {:?}", err);
+                    }
+                }
+            }
+        });
+    }
+
     tokio::spawn({
         let app_config = app_config.clone();
         let zenoh_session = zenoh_session.clone();
+        let metrics = metrics.clone();
         async move {
             if let Err(err) = start_event_publisher(
                 zenoh_session.clone(),
                 app_config.app.clone(),
                 audio_detector_event_receiver,
+                status,
+                metrics,
+                app_config
+                    .picovoice
+                    .voice_probability_telemetry
+                    .pretty_print,
             )
             .await
             {
@@ -174,61 +479,231 @@ async fn main() -> anyhow::Result<()> {
         .await
         .map_err(WakewordError::ZenohError)?;
 
-    let wake_word_audio_recording_wav_publisher = zenoh_session
-        .declare_publisher(app_config.app.get_wake_word_audio_recording_wav_topic())
+    let transcription_failed_publisher = zenoh_session
+        .declare_publisher(app_config.app.get_transcription_failed_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    let intent_publisher = zenoh_session
+        .declare_publisher(app_config.app.get_intent_topic())
         .res()
         .await
         .map_err(WakewordError::ZenohError)?;
 
+    let transcript_filtered_publisher = zenoh_session
+        .declare_publisher(app_config.app.get_transcript_filtered_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    let transcript_dismissed_publisher = zenoh_session
+        .declare_publisher(app_config.app.get_transcript_dismissed_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    let transcript_filter = TranscriptFilter::new(&app_config.transcript_filter)?;
+    let dismiss_phrases = DismissPhrases::new(&app_config.dismiss_phrases())?;
+    let transcript_router = TranscriptRouter::new(&app_config.transcript_routes)?;
+    let intent_parser = IntentParser::new(&app_config.intents)?;
+    if app_config.system_prompt.default_prompt.is_none() {
+        warn!("No default system prompt. Wake words without system prompt use an empty prompt");
+    }
+    let mut system_prompts = SystemPrompts::new(
+        app_config.app.system_prompts.clone(),
+        app_config.system_prompt.clone(),
+        prompt_context,
+    );
+
+    let transcriber = Transcriber::new(
+        open_ai_client.clone(),
+        app_config.transcription.clone(),
+        app_config.app.transcription_audio_format,
+    );
+
     while let Some(audio_sample) = audio_sample_receiver.recv().await {
-        let system_prompt = app_config.app.system_prompts.get(&audio_sample.wake_word);
+        metrics.observe(
+            &RECORDING_DURATION,
+            &[],
+            audio_sample.duration().as_secs_f64(),
+        );
+        if let Err(err) = publish_recording(&audio_sample, &zenoh_session, &app_config.app).await {
+            tracing::error!("Error publishing recording: {:?}", err);
+        }
 
-        let system_prompt = match system_prompt {
-            Some(sys) => sys.as_str(),
-            None => {
+        // drop recordings that waited in queue for too long
+        // transcription only gets the remaining time so that no transcript arrives later than max queue age
+        let queue_age = chrono::Utc::now()
+            .signed_duration_since(audio_sample.end_timestamp)
+            .to_std()
+            .unwrap_or_default();
+        let max_queue_age = app_config.transcription.max_queue_age();
+        let remaining_queue_age = match max_queue_age.checked_sub(queue_age) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
                 tracing::warn!(
-                    "No system prompt for wake word {:?}",
-                    audio_sample.wake_word
+                    "Dropping recording {} that waited {:?} for transcription",
+                    audio_sample.recording_id,
+                    queue_age
                 );
-                // return empty string
-                ""
+                publish_transcription_failed(
+                    &transcription_failed_publisher,
+                    &metrics,
+                    &audio_sample,
+                    &TranscriptionError::Stale(max_queue_age),
+                )
+                .await;
+                continue;
             }
         };
 
-        match transcribe(
-            &audio_sample,
-            system_prompt,
-            &open_ai_client,
-            &wake_word_audio_recording_wav_publisher,
+        let system_prompt = system_prompts.render(&audio_sample.wake_word, chrono::Local::now());
+
+        let keyword_settings = app_config
+            .picovoice
+            .keyword_settings
+            .get(&audio_sample.wake_word)
+            .cloned()
+            .unwrap_or_default();
+
+        let transcription_started = std::time::Instant::now();
+        let transcription = tokio::time::timeout(
+            remaining_queue_age,
+            transcriber.transcribe(
+                &audio_sample,
+                &system_prompt,
+                keyword_settings.transcription_language(),
+            ),
         )
         .await
-        {
-            Ok(transcript) => {
-                tracing::info!("Transcript {:?}", transcript);
-
-                if let Some(dismiss_keyword) = &app_config.picovoice.dismiss_keyword {
-                    if transcript.to_lowercase().contains(dismiss_keyword) {
-                        tracing::info!(
-                            "Dismiss keyword detected in transcript. Dismissing transcript"
-                        );
-                        continue;
+        .unwrap_or(Err(TranscriptionError::Stale(max_queue_age)));
+        metrics.observe(
+            &TRANSCRIPTION_LATENCY,
+            &[],
+            transcription_started.elapsed().as_secs_f64(),
+        );
+
+        match transcription {
+            Ok(transcription) => {
+                tracing::info!("Transcript {:?}", transcription.text);
+
+                let filter_reason = match app_config.transcription.no_speech_probability_threshold {
+                    Some(threshold) if transcription.is_likely_silence(threshold) => {
+                        Some(TranscriptFilterReason::NoSpeech)
+                    }
+                    _ => transcript_filter.check(
+                        &audio_sample.wake_word,
+                        &transcription.text,
+                        audio_sample.duration(),
+                    ),
+                };
+                if let Some(reason) = filter_reason {
+                    tracing::info!("Transcript filtered out because of {:?}", reason);
+                    let transcript_filtered = TranscriptFiltered {
+                        recording_id: audio_sample.recording_id,
+                        wake_word: audio_sample.wake_word,
+                        timestamp: audio_sample.timestamp,
+                        transcript: transcription.text,
+                        reason,
+                    };
+                    if let Err(err) =
+                        publish_message(&transcript_filtered_publisher, &transcript_filtered).await
+                    {
+                        tracing::error!("Error publishing filtered transcript: {:?}", err);
                     }
+                    continue;
                 }
 
+                if let Some(dismiss_rule) = dismiss_phrases.check(&transcription.text) {
+                    tracing::info!(
+                        "Dismiss phrase {:?} detected in transcript. Dismissing transcript",
+                        dismiss_rule
+                    );
+                    // recording already ended so this is reported separately from recording end
+                    metrics.increment(&DISMISSALS, &[("rule", dismiss_rule.kind())]);
+                    if let Some(dataset_recorder) = &dataset_recorder {
+                        dataset_recorder.save_recording(&audio_sample, DatasetLabel::Dismissed);
+                    }
+                    let transcript_dismissed = TranscriptDismissed {
+                        recording_id: audio_sample.recording_id,
+                        wake_word: audio_sample.wake_word,
+                        timestamp: audio_sample.timestamp,
+                        transcript: transcription.text,
+                        dismiss_rule,
+                    };
+                    if let Err(err) =
+                        publish_message(&transcript_dismissed_publisher, &transcript_dismissed)
+                            .await
+                    {
+                        tracing::error!("Error publishing dismissed transcript: {:?}", err);
+                    }
+                    continue;
+                }
+
+                // only recordings that weren't filtered or dismissed count as accepted
+                if let Some(dataset_recorder) = dataset_recorder
+                    .as_ref()
+                    .filter(|recorder| recorder.should_save_accepted(audio_sample.recording_id))
+                {
+                    dataset_recorder.save_recording(&audio_sample, DatasetLabel::Accepted);
+                }
+
+                system_prompts.add_transcript(&transcription.text);
+
+                let route = transcript_router.route(&audio_sample.wake_word, &transcription.text);
+                let intent_detected = intent_parser
+                    .parse(&audio_sample.wake_word, &transcription.text)
+                    .map(|intent| IntentDetected {
+                        recording_id: audio_sample.recording_id,
+                        wake_word: audio_sample.wake_word.clone(),
+                        timestamp: audio_sample.timestamp,
+                        intent: intent.intent.to_owned(),
+                        slots: intent.slots,
+                        transcript: transcription.text.clone(),
+                    });
                 let transcript = AudioTranscript {
+                    recording_id: audio_sample.recording_id,
                     wake_word: audio_sample.wake_word,
                     timestamp: audio_sample.timestamp,
-                    transcript,
+                    transcript: transcription.text,
+                    language: transcription.language,
+                    duration: transcription.duration,
+                    segments: transcription.segments,
                 };
-                let transcript_json = serde_json::to_string(&transcript)?;
-                transcript_publisher
-                    .put(transcript_json)
-                    .res()
-                    .await
-                    .map_err(WakewordError::ZenohError)?;
+                if let Some(route) = route {
+                    tracing::info!("Transcript matched route {:?}", route.name);
+                    let routed_transcript = RoutedTranscript {
+                        route: route.name.to_owned(),
+                        transcript,
+                        captures: route.captures,
+                    };
+                    if let Err(err) =
+                        publish_routed_transcript(&zenoh_session, route.topic, &routed_transcript)
+                            .await
+                    {
+                        tracing::error!("Error publishing routed transcript: {:?}", err);
+                    }
+                } else if let Err(err) = publish_message(&transcript_publisher, &transcript).await {
+                    tracing::error!("Error publishing transcript: {:?}", err);
+                }
+
+                if let Some(intent_detected) = intent_detected {
+                    tracing::info!("Detected intent {:?}", intent_detected.intent);
+                    if let Err(err) = publish_message(&intent_publisher, &intent_detected).await {
+                        tracing::error!("Error publishing detected intent: {:?}", err);
+                    }
+                }
             }
             Err(err) => {
                 tracing::error!("Error transcribing audio: {:?}", err);
+                publish_transcription_failed(
+                    &transcription_failed_publisher,
+                    &metrics,
+                    &audio_sample,
+                    &err,
+                )
+                .await;
             }
         }
     }
@@ -236,36 +711,140 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn transcribe(
+/// Failed transcriptions are only reported so a publishing error must not stop the transcriber loop
+async fn publish_transcription_failed(
+    publisher: &zenoh::publication::Publisher<'_>,
+    metrics: &Metrics,
+    audio_sample: &AudioSample,
+    err: &TranscriptionError,
+) {
+    metrics.increment(
+        &TRANSCRIPTION_ERRORS,
+        &[("category", &enum_label(&err.category()))],
+    );
+    let transcription_failed =
+        TranscriptionFailed::new(audio_sample, err.category(), err.to_string());
+    let payload = match serde_json::to_string(&transcription_failed) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("Failed to serialize transcription failure: {:?}", err);
+            return;
+        }
+    };
+    if let Err(err) = publisher.put(payload).res().await {
+        tracing::error!("Failed to publish transcription failure: {:?}", err);
+    }
+}
+
+async fn publish_message(
+    publisher: &zenoh::publication::Publisher<'_>,
+    message: &impl serde::Serialize,
+) -> anyhow::Result<()> {
+    publisher
+        .put(serde_json::to_string(message)?)
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+    Ok(())
+}
+
+async fn publish_routed_transcript(
+    zenoh_session: &Session,
+    topic: &str,
+    routed_transcript: &RoutedTranscript,
+) -> anyhow::Result<()> {
+    zenoh_session
+        .put(topic, serde_json::to_string(routed_transcript)?)
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+    Ok(())
+}
+
+async fn publish_recording(
     audio_sample: &AudioSample,
-    system_prompt: &str,
-    open_ai_client: &OpenAiClient<OpenAIConfig>,
-    audio_publisher: &Publisher<'_>,
-) -> anyhow::Result<String> {
-    let wav_file = audio_sample.to_vaw_file()?;
-
-    audio_publisher
-        .put(wav_file.clone())
+    zenoh_session: &Session,
+    app_config: &AppConfig,
+) -> anyhow::Result<()> {
+    let recording_file = audio_sample.encode(app_config.recording_audio_format)?;
+    let audio_recording_json = serde_json::to_string(
+        &audio_sample
+            .to_audio_recording_message(app_config.recording_audio_format, &recording_file),
+    )?;
+    zenoh_session
+        .put(
+            app_config.get_wake_word_audio_recording_topic(),
+            audio_recording_json,
+        )
         .res()
         .await
         .map_err(WakewordError::ZenohError)?;
 
-    let audio_input = AudioInput::from_vec_u8(String::from("recorded.wav"), wav_file);
+    if app_config.publish_wav_recording {
+        let wav_file = audio_sample.to_vaw_file()?;
+        // recording ID is appended to the key so that consumers can correlate the audio with other events
+        zenoh_session
+            .put(
+                app_config.get_wake_word_audio_recording_wav_topic_for_recording(
+                    &audio_sample.recording_id,
+                ),
+                wav_file.clone(),
+            )
+            .res()
+            .await
+            .map_err(WakewordError::ZenohError)?;
+
+        if app_config.publish_legacy_wav_topic {
+            zenoh_session
+                .put(
+                    app_config.get_wake_word_audio_recording_wav_topic(),
+                    wav_file,
+                )
+                .res()
+                .await
+                .map_err(WakewordError::ZenohError)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn record_validation_result(status: &Mutex<WakewordStatus>, validation_result: &ValidationResult) {
+    match status.lock() {
+        Ok(mut status) => status
+            .validation
+            .entry(validation_result.wake_word.clone())
+            .or_default()
+            .record(validation_result.decision),
+        Err(_) => tracing::error!("Status lock poisoned"),
+    }
+}
 
-    let request = CreateTranscriptionRequestArgs::default()
-        .file(audio_input)
-        .model(VOICE_TO_TEXT_TRANSCRIBE_MODEL)
-        .language(VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE)
-        .prompt(system_prompt)
-        .build()?;
-    let response = open_ai_client.audio().transcribe(request).await?;
-    Ok(response.text)
+fn record_stage_timings(
+    status: &Mutex<WakewordStatus>,
+    stage_timings: &HashMap<String, LatencyHistogram>,
+) {
+    match status.lock() {
+        Ok(mut status) => {
+            for (stage, histogram) in stage_timings {
+                status
+                    .stage_timings
+                    .entry(stage.clone())
+                    .or_default()
+                    .merge(histogram);
+            }
+        }
+        Err(_) => tracing::error!("Status lock poisoned"),
+    }
 }
 
 async fn start_event_publisher(
     zenoh_session: Arc<Session>,
     app_config: AppConfig,
     mut audio_detector_event_receiver: tokio::sync::mpsc::Receiver<AudioDetectorData>,
+    status: Arc<Mutex<WakewordStatus>>,
+    metrics: Arc<Metrics>,
+    pretty_print_voice_probability: bool,
 ) -> anyhow::Result<()> {
     let voice_probability_publisher = zenoh_session
         .declare_publisher(app_config.get_voice_probability_topic())
@@ -275,13 +854,19 @@ async fn start_event_publisher(
         .await
         .map_err(WakewordError::ZenohError)?;
 
-    let voice_probability_pretty_print_publisher = zenoh_session
-        .declare_publisher(app_config.get_voice_probability_pretty_print_topic())
-        .priority(Priority::InteractiveLow)
-        .congestion_control(CongestionControl::Drop)
-        .res()
-        .await
-        .map_err(WakewordError::ZenohError)?;
+    let voice_probability_pretty_print_publisher = if pretty_print_voice_probability {
+        Some(
+            zenoh_session
+                .declare_publisher(app_config.get_voice_probability_pretty_print_topic())
+                .priority(Priority::InteractiveLow)
+                .congestion_control(CongestionControl::Drop)
+                .res()
+                .await
+                .map_err(WakewordError::ZenohError)?,
+        )
+    } else {
+        None
+    };
 
     let recording_started_publisher = zenoh_session
         .declare_publisher(app_config.get_wake_word_recording_started_topic())
@@ -301,6 +886,25 @@ async fn start_event_publisher(
         .await
         .map_err(WakewordError::ZenohError)?;
 
+    let transcription_queue_saturated_publisher = zenoh_session
+        .declare_publisher(app_config.get_transcription_queue_saturated_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    let validation_result_publisher = zenoh_session
+        .declare_publisher(app_config.get_validation_result_topic())
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
+    let stop_publisher = zenoh_session
+        .declare_publisher(app_config.get_stop_topic())
+        .priority(Priority::InteractiveHigh)
+        .res()
+        .await
+        .map_err(WakewordError::ZenohError)?;
+
     while let Some(event) = audio_detector_event_receiver.recv().await {
         match event {
             AudioDetectorData::VoiceProbability(voice_probability) => {
@@ -311,12 +915,14 @@ async fn start_event_publisher(
                     .await
                     .map_err(WakewordError::ZenohError)?;
 
-                let pretty_print = voice_activity_to_text(&voice_probability);
-                voice_probability_pretty_print_publisher
-                    .put(pretty_print)
-                    .res()
-                    .await
-                    .map_err(WakewordError::ZenohError)?;
+                if let Some(pretty_print_publisher) = &voice_probability_pretty_print_publisher {
+                    let pretty_print = voice_activity_to_text(&voice_probability);
+                    pretty_print_publisher
+                        .put(pretty_print)
+                        .res()
+                        .await
+                        .map_err(WakewordError::ZenohError)?;
+                }
             }
             AudioDetectorData::WakeWordDetected(wake_word_detection) => {
                 let wake_word_detection_json = serde_json::to_string(&wake_word_detection)?;
@@ -334,7 +940,63 @@ async fn start_event_publisher(
                     .await
                     .map_err(WakewordError::ZenohError)?;
             }
+            AudioDetectorData::ValidationResult(validation_result) => {
+                record_validation_result(&status, &validation_result);
+                metrics.increment(
+                    &VALIDATIONS,
+                    &[
+                        ("keyword", &validation_result.wake_word),
+                        ("decision", &enum_label(&validation_result.decision)),
+                    ],
+                );
+                validation_result_publisher
+                    .put(serde_json::to_string(&validation_result)?)
+                    .res()
+                    .await
+                    .map_err(WakewordError::ZenohError)?;
+            }
+            AudioDetectorData::StageTimings(stage_timings) => {
+                record_stage_timings(&status, &stage_timings);
+            }
+            AudioDetectorData::TranscriptionQueueSaturated(transcription_queue_saturated) => {
+                transcription_queue_saturated_publisher
+                    .put(serde_json::to_string(&transcription_queue_saturated)?)
+                    .res()
+                    .await
+                    .map_err(WakewordError::ZenohError)?;
+            }
+            AudioDetectorData::Command(command) => {
+                zenoh_session
+                    .put(command.topic, command.payload)
+                    .res()
+                    .await
+                    .map_err(WakewordError::ZenohError)?;
+            }
+            AudioDetectorData::Stop(stop_command) => {
+                let stop_command_json = serde_json::to_string(&stop_command)?;
+                stop_publisher
+                    .put(stop_command_json)
+                    .res()
+                    .await
+                    .map_err(WakewordError::ZenohError)?;
+            }
+            AudioDetectorData::FollowUpRecordingStarted(follow_up_recording_started) => {
+                let follow_up_recording_started_json =
+                    serde_json::to_string(&follow_up_recording_started)?;
+                recording_started_publisher
+                    .put(follow_up_recording_started_json)
+                    .res()
+                    .await
+                    .map_err(WakewordError::ZenohError)?;
+            }
             AudioDetectorData::RecordingEnd(wake_word_detection_end) => {
+                metrics.increment(
+                    &RECORDINGS,
+                    &[("reason", &enum_label(&wake_word_detection_end.reason()))],
+                );
+                if let Some(dismiss_rule) = wake_word_detection_end.dismiss_rule() {
+                    metrics.increment(&DISMISSALS, &[("rule", dismiss_rule.kind())]);
+                }
                 let wake_word_detection_end_json = serde_json::to_string(&wake_word_detection_end)?;
                 wake_word_detection_end_publisher
                     .put(wake_word_detection_end_json)
@@ -348,6 +1010,108 @@ async fn start_event_publisher(
     Ok(())
 }
 
+fn export_dataset_command(
+    config: &PicovoiceConfig,
+    output: &std::path::Path,
+) -> anyhow::Result<()> {
+    let dataset_capture = config
+        .dataset_capture
+        .as_ref()
+        .context("Dataset capture is not configured")?;
+    let statistics = dataset::export_dataset(&dataset_capture.directory, output)?;
+    info!("Exported dataset to {:?}", output);
+    for (keyword, keyword_statistics) in statistics {
+        info!(
+            "{:?}: {} rejected, {} dismissed, {} accepted, {:.1}s of audio",
+            keyword,
+            keyword_statistics.rejected,
+            keyword_statistics.dismissed,
+            keyword_statistics.accepted,
+            keyword_statistics.duration_ms as f32 / 1000.0
+        );
+    }
+    Ok(())
+}
+
+fn calibrate_command(
+    config: &PicovoiceConfig,
+    directory: &std::path::Path,
+    steps: usize,
+    max_false_alarms_per_hour: f32,
+    write_config: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let sensitivities = calibration::sensitivity_sweep(steps);
+    let calibrations =
+        calibration::calibrate(config, directory, &sensitivities, max_false_alarms_per_hour)?;
+
+    let mut recommended = vec![];
+    for calibration in calibrations {
+        match calibration.recommended {
+            Some(sensitivity) => {
+                let point = calibration
+                    .points
+                    .iter()
+                    .find(|point| point.sensitivity == sensitivity)
+                    .context("Recommended sensitivity not in sweep")?;
+                info!(
+                    "Recommended sensitivity for {:?} is {:.2} with detection rate {:.1}% and {:.2} false alarms per hour",
+                    calibration.keyword,
+                    sensitivity,
+                    point.detection_rate * 100.0,
+                    point.false_alarms_per_hour
+                );
+                recommended.push((calibration.keyword, sensitivity));
+            }
+            None => warn!(
+                "No sensitivity for {:?} stays within {} false alarms per hour",
+                calibration.keyword, max_false_alarms_per_hour
+            ),
+        }
+    }
+
+    if let Some(config_path) = write_config {
+        let config_text = std::fs::read_to_string(config_path)?;
+        let backup_path = config_path.with_extension("yaml.bak");
+        std::fs::write(&backup_path, &config_text)?;
+        std::fs::write(
+            config_path,
+            calibration::write_sensitivities(&config_text, &recommended)?,
+        )?;
+        info!(
+            "Wrote sensitivities to {:?}. Previous config saved to {:?}",
+            config_path, backup_path
+        );
+    }
+    Ok(())
+}
+
+fn bench_command(
+    config: &PicovoiceConfig,
+    wav: Option<&std::path::Path>,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let report = bench::run_bench(config, wav, duration)?;
+    info!(
+        "Processed {} frames of {:.1}s audio with {:.1}ms frame budget",
+        report.frames,
+        report.audio_duration.as_secs_f32(),
+        report.frame_budget.as_secs_f32() * 1000.0
+    );
+    for stage in &report.stages {
+        info!(
+            "{:<18} p50 {:>7.3}ms p90 {:>7.3}ms p99 {:>7.3}ms max {:>7.3}ms p99 budget {:>5.1}%",
+            stage.stage,
+            stage.p50.as_secs_f32() * 1000.0,
+            stage.p90.as_secs_f32() * 1000.0,
+            stage.p99.as_secs_f32() * 1000.0,
+            stage.max.as_secs_f32() * 1000.0,
+            stage.p99.as_secs_f32() / report.frame_budget.as_secs_f32() * 100.0
+        );
+    }
+    info!("Real time factor {:.3}", report.real_time_factor());
+    Ok(())
+}
+
 fn show_audio_devices(config: &PicovoiceConfig) {
     info!("Listing audio devices");
     let mut recorder_builder = PvRecorderBuilder::default();
@@ -400,3 +1164,68 @@ fn voice_activity_to_text(voice_probability: &VoiceProbability) -> String {
         recording_flare
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::ValidationDecision;
+
+    fn validation_result(wake_word: &str, decision: ValidationDecision) -> ValidationResult {
+        ValidationResult {
+            wake_word: wake_word.to_owned(),
+            recording_id: None,
+            transcript: None,
+            score: None,
+            latency_ms: 0,
+            decision,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn acceptance_rate_ignores_failed_validations() {
+        let status = Mutex::new(WakewordStatus::default());
+        let record = |wake_word: &str, decision| {
+            record_validation_result(&status, &validation_result(wake_word, decision))
+        };
+        record("Hey Hopper", ValidationDecision::FailedAccepted);
+        record("Hey Hopper", ValidationDecision::FailedRejected);
+        // nothing was validated yet
+        assert_eq!(
+            status.lock().unwrap().validation["Hey Hopper"].acceptance_rate,
+            None
+        );
+
+        record("Hey Hopper", ValidationDecision::Valid);
+        record("Hey Hopper", ValidationDecision::NotValid);
+        record("Hey Hopper", ValidationDecision::Valid);
+        record("Wintermute", ValidationDecision::NotValid);
+        let status = status.lock().unwrap();
+        let hopper = &status.validation["Hey Hopper"];
+        assert_eq!(hopper.failed, 2);
+        assert_eq!(hopper.acceptance_rate, Some(2.0 / 3.0));
+        assert_eq!(status.validation["Wintermute"].acceptance_rate, Some(0.0));
+    }
+
+    #[test]
+    fn stage_timings_accumulate_across_flushes() {
+        let status = Mutex::new(WakewordStatus::default());
+        let flush = |frame_us: &[u64]| {
+            let mut histogram = LatencyHistogram::default();
+            for duration in frame_us {
+                histogram.record(Duration::from_micros(*duration));
+            }
+            HashMap::from([(String::from(stage_timing::STAGE_FRAME), histogram)])
+        };
+        record_stage_timings(&status, &flush(&[800, 900]));
+        // flush of an idle interval doesn't reset totals
+        record_stage_timings(&status, &HashMap::new());
+        record_stage_timings(&status, &flush(&[40_000]));
+
+        let status = status.lock().unwrap();
+        let frame = &status.stage_timings[stage_timing::STAGE_FRAME];
+        assert_eq!(frame.count, 3);
+        assert_eq!(frame.max_us, 40_000);
+        assert_eq!(frame.sum_us, 41_700);
+    }
+}
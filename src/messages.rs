@@ -1,15 +1,36 @@
 use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
-use std::{io::Cursor, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    path::Path,
+};
+use uuid::Uuid;
+
+use crate::{
+    audio_encoding::{encode_flac, encode_ogg_opus, AudioFormat},
+    stage_timing::LatencyHistogram,
+};
 
 pub struct AudioSample {
+    pub recording_id: Uuid,
     pub data: Vec<i16>,
     pub wake_word: String,
     pub sample_rate: u32,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub end_timestamp: chrono::DateTime<chrono::Utc>,
+    /// Direction of arrival in degrees as reported by the ReSpeaker when the wake word was detected
+    pub direction_of_arrival: Option<i32>,
+    /// Audio around the wake word detection. Only captured for the dataset
+    pub detection_window: Vec<i16>,
 }
 
 impl AudioSample {
+    pub fn duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.data.len() as f64 / self.sample_rate as f64)
+    }
+
     #[allow(unused)]
     pub fn write_to_wav_file(&self, output_path: &Path) -> anyhow::Result<()> {
         let wavspec = hound::WavSpec {
@@ -51,6 +72,58 @@ impl AudioSample {
 
         Ok(file)
     }
+
+    pub fn to_flac_file(&self) -> anyhow::Result<Vec<u8>> {
+        encode_flac(&self.data, self.sample_rate).context("Failed to encode FLAC file")
+    }
+
+    pub fn to_ogg_opus_file(&self) -> anyhow::Result<Vec<u8>> {
+        encode_ogg_opus(&self.data, self.sample_rate).context("Failed to encode Ogg Opus file")
+    }
+
+    pub fn encode(&self, format: AudioFormat) -> anyhow::Result<Vec<u8>> {
+        match format {
+            AudioFormat::Wav => self.to_vaw_file(),
+            AudioFormat::Flac => self.to_flac_file(),
+            AudioFormat::OggOpus => self.to_ogg_opus_file(),
+        }
+    }
+
+    /// Wrap encoded audio file with metadata about this sample
+    pub fn to_audio_recording_message(
+        &self,
+        format: AudioFormat,
+        audio_file: &[u8],
+    ) -> AudioRecording {
+        AudioRecording {
+            recording_id: self.recording_id,
+            wake_word: self.wake_word.clone(),
+            timestamp: self.timestamp,
+            end_timestamp: self.end_timestamp,
+            sample_rate: self.sample_rate,
+            duration_ms: self.duration().as_millis() as u64,
+            direction_of_arrival: self.direction_of_arrival,
+            format,
+            audio_base64: BASE64_STANDARD.encode(audio_file),
+        }
+    }
+}
+
+/// Recorded audio together with metadata describing it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AudioRecording {
+    pub recording_id: Uuid,
+    pub wake_word: String,
+    /// Time of wake word detection
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Time when recording finished
+    pub end_timestamp: chrono::DateTime<chrono::Utc>,
+    pub sample_rate: u32,
+    pub duration_ms: u64,
+    pub direction_of_arrival: Option<i32>,
+    pub format: AudioFormat,
+    /// Base64 encoded audio file in `format`
+    pub audio_base64: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,6 +131,147 @@ pub struct PrivacyModeCommand {
     pub privacy_mode: bool,
 }
 
+/// Published when an interrupt keyword is detected
+/// Downstream speech and motion nodes should stop what they are doing
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopCommand {
+    wake_word: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    /// Recording that was active when the keyword was detected
+    recording_id: Option<Uuid>,
+}
+
+impl StopCommand {
+    pub fn new(
+        wake_word: String,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        recording_id: Option<Uuid>,
+    ) -> Self {
+        Self {
+            wake_word,
+            timestamp,
+            recording_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationDecision {
+    /// Backend found the wake word
+    Valid,
+    /// Backend didn't find the wake word
+    NotValid,
+    /// Validation failed or timed out and failure policy accepted the detection
+    FailedAccepted,
+    /// Validation failed or timed out and failure policy rejected the detection
+    FailedRejected,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ValidationResult {
+    pub wake_word: String,
+    pub recording_id: Option<Uuid>,
+    /// Transcript returned by Whisper validation
+    pub transcript: Option<String>,
+    pub score: Option<f32>,
+    pub latency_ms: u64,
+    pub decision: ValidationDecision,
+    pub error: Option<String>,
+}
+
+/// Running validation counters for a single keyword
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ValidationCounters {
+    pub valid: u64,
+    pub not_valid: u64,
+    pub failed: u64,
+    /// Share of validated detections accepted by the backend
+    ///
+    /// Not precision because it's unknown which detections were real wake words
+    pub acceptance_rate: Option<f32>,
+}
+
+impl ValidationCounters {
+    pub fn record(&mut self, decision: ValidationDecision) {
+        match decision {
+            ValidationDecision::Valid => self.valid += 1,
+            ValidationDecision::NotValid => self.not_valid += 1,
+            ValidationDecision::FailedAccepted | ValidationDecision::FailedRejected => {
+                self.failed += 1
+            }
+        }
+        let validated = self.valid + self.not_valid;
+        if validated > 0 {
+            self.acceptance_rate = Some(self.valid as f32 / validated as f32);
+        }
+    }
+}
+
+/// Reply of the status queryable
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct WakewordStatus {
+    pub validation: HashMap<String, ValidationCounters>,
+    /// Frame processing time per stage if enabled with `stage_timings`
+    pub stage_timings: HashMap<String, LatencyHistogram>,
+}
+
+/// Metrics published periodically on the telemetry topic
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricsSnapshot {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub counters: Vec<CounterValue>,
+    pub histograms: Vec<HistogramValue>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CounterValue {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistogramValue {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub count: u64,
+    /// Sum of observed values in seconds
+    pub sum: f64,
+    /// Upper bound in seconds and cumulative count of observations
+    pub buckets: Vec<(f64, u64)>,
+}
+
+/// Rendered payload of a command keyword
+#[derive(Debug)]
+pub struct KeywordCommandMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Published by TTS node while the robot is speaking
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpeakingStatus {
+    pub speaking: bool,
+}
+
+/// Vocabulary used to bias transcription, for example names of recognised people
+/// Replaces previous vocabulary from the same source. Empty vocabulary clears it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PromptContextCommand {
+    pub source: String,
+    pub vocabulary: Vec<String>,
+}
+
+/// Sent by downstream consumers that expect user to respond without repeating the wake word
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExpectFollowUpCommand {
+    /// Recording being followed up. Last finished recording is used if not set
+    pub recording_id: Option<Uuid>,
+    /// Window in which human speech starts a new recording
+    pub timeout_ms: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VoiceProbability {
     /// 0.0 to 1.0
@@ -65,6 +279,8 @@ pub struct VoiceProbability {
     timestamp: chrono::DateTime<chrono::Utc>,
     pub time_since_last_human_ms: u64,
     pub currently_recording: bool,
+    /// ID of the active recording if there is one
+    recording_id: Option<Uuid>,
 }
 
 impl VoiceProbability {
@@ -72,13 +288,14 @@ impl VoiceProbability {
         probability: f32,
         timestamp: chrono::DateTime<chrono::Utc>,
         time_since_last_human_ms: u64,
-        currently_recording: bool,
+        recording_id: Option<Uuid>,
     ) -> Self {
         Self {
             probability,
             timestamp,
             time_since_last_human_ms,
-            currently_recording,
+            currently_recording: recording_id.is_some(),
+            recording_id,
         }
     }
 }
@@ -87,13 +304,49 @@ impl VoiceProbability {
 pub struct WakeWordDetection {
     wake_word: String,
     timestamp: chrono::DateTime<chrono::Utc>,
+    /// Recording this detection started or happened during
+    /// None for detections that don't belong to a recording such as dismiss keywords
+    recording_id: Option<Uuid>,
 }
 
 impl WakeWordDetection {
-    pub fn new(wake_word: String, timestamp: chrono::DateTime<chrono::Utc>) -> Self {
+    pub fn new(
+        wake_word: String,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        recording_id: Option<Uuid>,
+    ) -> Self {
         Self {
             wake_word,
             timestamp,
+            recording_id,
+        }
+    }
+}
+
+/// Recording started by speech during follow up window without a wake word
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FollowUpRecordingStarted {
+    /// Wake word of the original recording
+    wake_word: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    recording_id: Uuid,
+    previous_recording_id: Uuid,
+    follow_up: bool,
+}
+
+impl FollowUpRecordingStarted {
+    pub fn new(
+        wake_word: String,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        recording_id: Uuid,
+        previous_recording_id: Uuid,
+    ) -> Self {
+        Self {
+            wake_word,
+            timestamp,
+            recording_id,
+            previous_recording_id,
+            follow_up: true,
         }
     }
 }
@@ -103,6 +356,9 @@ pub struct WakeWordDetectionEnd {
     wake_word: String,
     timestamp: chrono::DateTime<chrono::Utc>,
     reason: DetectionEndReason,
+    recording_id: Uuid,
+    /// Rule that dismissed the recording
+    dismiss_rule: Option<DismissRule>,
 }
 
 impl WakeWordDetectionEnd {
@@ -110,11 +366,45 @@ impl WakeWordDetectionEnd {
         wake_word: String,
         timestamp: chrono::DateTime<chrono::Utc>,
         reason: DetectionEndReason,
+        recording_id: Uuid,
     ) -> Self {
         Self {
             wake_word,
             timestamp,
             reason,
+            recording_id,
+            dismiss_rule: None,
+        }
+    }
+
+    pub fn with_dismiss_rule(mut self, dismiss_rule: DismissRule) -> Self {
+        self.dismiss_rule = Some(dismiss_rule);
+        self
+    }
+
+    pub fn reason(&self) -> DetectionEndReason {
+        self.reason
+    }
+
+    pub fn dismiss_rule(&self) -> Option<&DismissRule> {
+        self.dismiss_rule.as_ref()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum DismissRule {
+    /// Dismiss keyword detected by Porcupine
+    Keyword { keyword: String },
+    /// Dismiss phrase found in transcript
+    Phrase { phrase: String },
+}
+
+impl DismissRule {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DismissRule::Keyword { .. } => "keyword",
+            DismissRule::Phrase { .. } => "phrase",
         }
     }
 }
@@ -129,9 +419,162 @@ pub enum DetectionEndReason {
     ValidationFailed,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscriptionErrorCategory {
+    /// Transcription request didn't finish in time
+    Timeout,
+    /// Failed to reach transcription API
+    Network,
+    /// Transcription API returned an error
+    Api,
+    /// Recording wasn't transcribed within maximum queue age and was dropped
+    Stale,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptionFailed {
+    recording_id: Uuid,
+    wake_word: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    category: TranscriptionErrorCategory,
+    error: String,
+}
+
+impl TranscriptionFailed {
+    pub fn new(
+        audio_sample: &AudioSample,
+        category: TranscriptionErrorCategory,
+        error: String,
+    ) -> Self {
+        Self {
+            recording_id: audio_sample.recording_id,
+            wake_word: audio_sample.wake_word.clone(),
+            timestamp: audio_sample.timestamp,
+            category,
+            error,
+        }
+    }
+}
+
+/// Warning that recordings are waiting because transcription can't keep up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptionQueueSaturated {
+    recording_id: Uuid,
+    wake_word: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    /// Recordings held back by the listener including this one
+    pending_recordings: usize,
+    queue_size: usize,
+}
+
+impl TranscriptionQueueSaturated {
+    pub fn new(audio_sample: &AudioSample, pending_recordings: usize, queue_size: usize) -> Self {
+        Self {
+            recording_id: audio_sample.recording_id,
+            wake_word: audio_sample.wake_word.clone(),
+            timestamp: audio_sample.timestamp,
+            pending_recordings,
+            queue_size,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AudioTranscript {
+    pub recording_id: Uuid,
     pub wake_word: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub transcript: String,
+    /// Language detected by Whisper with verbose or automatic language transcription
+    /// Otherwise the requested language
+    pub language: Option<String>,
+    /// Following fields are only included with verbose transcription
+    /// Duration of transcribed audio in seconds
+    pub duration: Option<f32>,
+    pub segments: Option<Vec<TranscriptSegment>>,
+}
+
+/// Transcript published on the topic of a matching route
+#[derive(Serialize, Debug)]
+pub struct RoutedTranscript {
+    pub route: String,
+    #[serde(flatten)]
+    pub transcript: AudioTranscript,
+    /// Named capture groups of the route pattern
+    /// Nested so that group names can't collide with transcript fields
+    pub captures: HashMap<String, String>,
+}
+
+/// Intent matched by the local grammar parser
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntentDetected {
+    pub recording_id: Uuid,
+    pub wake_word: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub intent: String,
+    pub slots: HashMap<String, String>,
+    pub transcript: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum TranscriptFilterReason {
+    /// All segments likely contain no speech
+    NoSpeech,
+    Blocklisted {
+        phrase: String,
+    },
+    TooFewWords {
+        word_count: usize,
+    },
+    RepeatedWord {
+        word: String,
+        repeats: usize,
+    },
+    /// Phrase of multiple words repeated back to back
+    RepeatedPhrase {
+        phrase: String,
+        repeats: usize,
+    },
+    TooManyCharactersPerSecond {
+        characters_per_second: f32,
+    },
+    /// Transcript didn't match any allow pattern for the wake word
+    NotAllowed,
+    Denied {
+        pattern: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptFiltered {
+    pub recording_id: Uuid,
+    pub wake_word: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub transcript: String,
+    pub reason: TranscriptFilterReason,
+}
+
+/// Transcript of a finished recording dismissed by a dismiss phrase
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptDismissed {
+    pub recording_id: Uuid,
+    pub wake_word: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub transcript: String,
+    pub dismiss_rule: DismissRule,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscriptSegment {
+    /// Start of segment in seconds
+    pub start: f32,
+    /// End of segment in seconds
+    pub end: f32,
+    pub text: String,
+    pub avg_logprob: f32,
+    /// Probability that segment contains no speech
+    pub no_speech_prob: f32,
 }
@@ -0,0 +1,407 @@
+//! Counters and histograms exposed in Prometheus text format
+//!
+//! Format is described in <https://prometheus.io/docs/instrumenting/exposition_formats/>
+
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::messages::{CounterValue, HistogramValue, MetricsSnapshot};
+
+pub struct MetricDescription {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub const DETECTIONS: MetricDescription = MetricDescription {
+    name: "wakeword_detections_total",
+    help: "Keyword detections by Porcupine",
+};
+pub const DISMISSALS: MetricDescription = MetricDescription {
+    name: "wakeword_dismissals_total",
+    help: "Recordings dismissed by keyword or transcript phrase",
+};
+pub const VALIDATIONS: MetricDescription = MetricDescription {
+    name: "wakeword_validations_total",
+    help: "Wake word validation decisions",
+};
+pub const RECORDINGS: MetricDescription = MetricDescription {
+    name: "wakeword_recordings_total",
+    help: "Ended recordings by reason",
+};
+pub const RECORDING_DURATION: MetricDescription = MetricDescription {
+    name: "wakeword_recording_duration_seconds",
+    help: "Duration of recordings sent for transcription",
+};
+pub const TRANSCRIPTION_LATENCY: MetricDescription = MetricDescription {
+    name: "wakeword_transcription_latency_seconds",
+    help: "Time to transcribe a recording including retries",
+};
+pub const TRANSCRIPTION_ERRORS: MetricDescription = MetricDescription {
+    name: "wakeword_transcription_errors_total",
+    help: "Failed transcriptions by category",
+};
+pub const MESSAGES_DROPPED: MetricDescription = MetricDescription {
+    name: "wakeword_messages_dropped_total",
+    help: "Messages dropped because internal channel was full",
+};
+pub const MESSAGES_DELAYED: MetricDescription = MetricDescription {
+    name: "wakeword_messages_delayed_total",
+    help: "Messages held back because internal channel was full",
+};
+pub const RESPEAKER_ERRORS: MetricDescription = MetricDescription {
+    name: "wakeword_respeaker_errors_total",
+    help: "ReSpeaker USB communication errors",
+};
+
+/// Clients that don't send a request or read the response in time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of histogram buckets in seconds
+const HISTOGRAM_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Name of unit enum variant as serialized in messages
+pub fn enum_label<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(label)) => label,
+        _ => String::from("unknown"),
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    bucket_counts: [u64; HISTOGRAM_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    help: BTreeMap<&'static str, &'static str>,
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Metrics {
+    fn state(&self) -> MutexGuard<'_, MetricsState> {
+        // partially updated metrics are still better than none
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn increment(&self, metric: &MetricDescription, labels: &[(&'static str, &str)]) {
+        let mut state = self.state();
+        state.help.insert(metric.name, metric.help);
+        *state
+            .counters
+            .entry((metric.name, owned_labels(labels)))
+            .or_default() += 1;
+    }
+
+    pub fn observe(&self, metric: &MetricDescription, labels: &[(&'static str, &str)], value: f64) {
+        let mut state = self.state();
+        state.help.insert(metric.name, metric.help);
+        let histogram = state
+            .histograms
+            .entry((metric.name, owned_labels(labels)))
+            .or_default();
+        if let Some(bucket) = HISTOGRAM_BUCKETS.iter().position(|bound| value <= *bound) {
+            histogram.bucket_counts[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += value;
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state();
+        let mut output = String::new();
+        let mut previous_name = None;
+        for ((name, labels), value) in &state.counters {
+            if previous_name != Some(name) {
+                write_header(&mut output, name, state.help[name], "counter");
+                previous_name = Some(name);
+            }
+            _ = writeln!(output, "{}{} {}", name, format_labels(labels, None), value);
+        }
+        for ((name, labels), histogram) in &state.histograms {
+            if previous_name != Some(name) {
+                write_header(&mut output, name, state.help[name], "histogram");
+                previous_name = Some(name);
+            }
+            let mut cumulative = 0;
+            for (bound, count) in HISTOGRAM_BUCKETS.iter().zip(histogram.bucket_counts) {
+                cumulative += count;
+                _ = writeln!(
+                    output,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(labels, Some(&bound.to_string())),
+                    cumulative
+                );
+            }
+            _ = writeln!(
+                output,
+                "{}_bucket{} {}",
+                name,
+                format_labels(labels, Some("+Inf")),
+                histogram.count
+            );
+            let labels = format_labels(labels, None);
+            _ = writeln!(output, "{}_sum{} {}", name, labels, histogram.sum);
+            _ = writeln!(output, "{}_count{} {}", name, labels, histogram.count);
+        }
+        output
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let state = self.state();
+        let labels_map = |labels: &Labels| {
+            labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect()
+        };
+        MetricsSnapshot {
+            timestamp: chrono::Utc::now(),
+            counters: state
+                .counters
+                .iter()
+                .map(|((name, labels), value)| CounterValue {
+                    name: name.to_string(),
+                    labels: labels_map(labels),
+                    value: *value,
+                })
+                .collect(),
+            histograms: state
+                .histograms
+                .iter()
+                .map(|((name, labels), histogram)| HistogramValue {
+                    name: name.to_string(),
+                    labels: labels_map(labels),
+                    count: histogram.count,
+                    sum: histogram.sum,
+                    buckets: HISTOGRAM_BUCKETS
+                        .iter()
+                        .zip(histogram.bucket_counts)
+                        .scan(0, |cumulative, (bound, count)| {
+                            *cumulative += count;
+                            Some((*bound, *cumulative))
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn owned_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect()
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, kind: &str) {
+    _ = writeln!(output, "# HELP {} {}", name, help);
+    _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut formatted: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        formatted.push(format!("le=\"{}\"", le));
+    }
+    if formatted.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", formatted.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn http_response(request: &str, metrics: &Metrics) -> String {
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => {
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        }
+    }
+}
+
+/// Minimal HTTP server answering `GET /metrics`
+pub async fn serve_metrics(address: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Serving metrics on http://{}/metrics", address);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_request(&mut stream, &metrics, REQUEST_TIMEOUT).await {
+                warn!("Error serving metrics request: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn serve_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    metrics: &Metrics,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let exchange = async {
+        // request headers fit easily, body isn't expected
+        let mut buffer = [0; 2048];
+        let read = stream.read(&mut buffer).await?;
+        let request = String::from_utf8_lossy(&buffer[..read]);
+        stream
+            .write_all(http_response(&request, metrics).as_bytes())
+            .await?;
+        stream.shutdown().await?;
+        Ok(())
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow::anyhow!(
+                "Metrics request timed out after {:?}",
+                timeout
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::DetectionEndReason;
+
+    #[test]
+    fn counters_and_histograms() {
+        let metrics = Metrics::default();
+        metrics.increment(&DETECTIONS, &[("keyword", "Hey Hopper")]);
+        metrics.increment(&DETECTIONS, &[("keyword", "Hey Hopper")]);
+        metrics.increment(&DETECTIONS, &[("keyword", "say \"hi\"")]);
+        metrics.increment(&RESPEAKER_ERRORS, &[]);
+        metrics.observe(&RECORDING_DURATION, &[], 0.3);
+        metrics.observe(&RECORDING_DURATION, &[], 75.0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE wakeword_detections_total counter\n"));
+        assert!(rendered.contains("wakeword_detections_total{keyword=\"Hey Hopper\"} 2\n"));
+        assert!(rendered.contains("wakeword_detections_total{keyword=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(rendered.contains("wakeword_respeaker_errors_total 1\n"));
+        assert!(rendered.contains("# TYPE wakeword_recording_duration_seconds histogram\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_bucket{le=\"0.25\"} 0\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_bucket{le=\"60\"} 1\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_sum 75.3\n"));
+        assert!(rendered.contains("wakeword_recording_duration_seconds_count 2\n"));
+        // header written once per metric
+        assert_eq!(
+            rendered.matches("# HELP wakeword_detections_total").count(),
+            1
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.counters.len(), 3);
+        assert_eq!(snapshot.histograms[0].buckets[2], (0.5, 1));
+        assert_eq!(snapshot.histograms[0].count, 2);
+    }
+
+    #[test]
+    fn labels_from_enums() {
+        assert_eq!(
+            enum_label(&DetectionEndReason::PrivacyModeActivated),
+            "privacy_mode_activated"
+        );
+    }
+
+    #[test]
+    fn http_routes() {
+        let metrics = Metrics::default();
+        metrics.increment(&RESPEAKER_ERRORS, &[]);
+        let response = http_response("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n", &metrics);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("wakeword_respeaker_errors_total 1\n"));
+
+        let response = http_response("GET / HTTP/1.1\r\n\r\n", &metrics);
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        // closed before sending anything
+        let response = http_response("", &metrics);
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let metrics = Metrics::default();
+        let (mut server, _client) = tokio::io::duplex(4096);
+        let result = serve_request(&mut server, &metrics, Duration::from_millis(10)).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn client_not_reading_response_times_out() {
+        let metrics = Metrics::default();
+        // response doesn't fit into the pipe until client reads it
+        let (mut server, mut client) = tokio::io::duplex(64);
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let result = serve_request(&mut server, &metrics, Duration::from_millis(10)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_request_within_timeout() {
+        let metrics = Metrics::default();
+        metrics.increment(&RESPEAKER_ERRORS, &[]);
+        let (mut server, mut client) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        serve_request(&mut server, &metrics, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("wakeword_respeaker_errors_total 1\n"));
+    }
+}
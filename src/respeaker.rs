@@ -4,13 +4,20 @@
 //! Code based on <https://github.com/respeaker/pixel_ring/blob/master/pixel_ring/usb_pixel_ring_v2.py>
 //! and <https://github.com/respeaker/usb_4_mic_array/blob/master/tuning.py>
 
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::{thread, time::Duration};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use rusb::{Context, DeviceHandle, UsbContext};
 use tracing::{error, info, warn};
 
+use crate::metrics::{Metrics, RESPEAKER_ERRORS};
+
 fn find_usb_device(vid: u16, pid: u16) -> Result<Option<PixelRing<Context>>> {
     let context = Context::new()?;
     let devices = context.devices()?;
@@ -73,7 +80,6 @@ impl<T: UsbContext> PixelRing<T> {
     }
 
     /// speak mode
-    #[allow(unused)]
     fn speak(&mut self) -> Result<()> {
         self.write(3, &[0])
     }
@@ -178,6 +184,12 @@ impl<T: UsbContext> PixelRing<T> {
     }
 }
 
+/// How often the ReSpeaker loop refreshes the cached direction of arrival
+const READ_DIRECTION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Cached direction value meaning no direction has been read
+const NO_DIRECTION: i32 = i32::MIN;
+
 const VENDOR_ID: u16 = 0x2886;
 const PRODUCE_ID: u16 = 0x0018;
 
@@ -197,12 +209,14 @@ enum SpeakerCommand {
     Off,
     Listen,
     Think,
-    ReadDirection(SyncSender<i32>),
+    Speak,
 }
 
 #[derive(Debug, Clone)]
 pub struct ReSpeakerCommander {
     sender: SyncSender<SpeakerCommand>,
+    /// Last direction of arrival read by the ReSpeaker loop. `None` for dummy
+    direction: Option<Arc<AtomicI32>>,
 }
 
 impl ReSpeakerCommander {
@@ -210,7 +224,10 @@ impl ReSpeakerCommander {
     pub fn dummy() -> Self {
         warn!("Using dummy ReSpeakerCommander");
         let (sender, _receiver) = sync_channel(10);
-        ReSpeakerCommander { sender }
+        ReSpeakerCommander {
+            sender,
+            direction: None,
+        }
     }
 
     pub fn off(&self) {
@@ -226,32 +243,51 @@ impl ReSpeakerCommander {
         _ = self.sender.try_send(SpeakerCommand::Think);
     }
 
-    #[allow(unused)]
-    pub fn read_direction(&self) -> Result<i32> {
-        let (sender, receiver) = sync_channel(1);
-        self.sender
-            .try_send(SpeakerCommand::ReadDirection(sender))?;
-        Ok(receiver.recv()?)
+    pub fn speak(&self) {
+        _ = self.sender.try_send(SpeakerCommand::Speak);
+    }
+
+    /// Last direction of arrival read by the ReSpeaker loop
+    ///
+    /// Doesn't block. Returns `None` for dummy or while the ReSpeaker isn't available
+    pub fn read_direction(&self) -> Option<i32> {
+        let direction = self.direction.as_ref()?.load(Ordering::Relaxed);
+        (direction != NO_DIRECTION).then_some(direction)
     }
 }
 
-pub fn start_respeaker_loop() -> ReSpeakerCommander {
+pub fn start_respeaker_loop(metrics: Arc<Metrics>) -> ReSpeakerCommander {
     info!("Starting ReSpeaker loop");
     let (sender, receiver) = sync_channel(10);
-    thread::spawn(move || respeaker_loop(receiver));
+    let direction = Arc::new(AtomicI32::new(NO_DIRECTION));
+    let loop_direction = direction.clone();
+    thread::spawn(move || respeaker_loop(receiver, loop_direction, metrics));
 
-    ReSpeakerCommander { sender }
+    ReSpeakerCommander {
+        sender,
+        direction: Some(direction),
+    }
 }
 
-fn respeaker_loop(mut command_receiver: Receiver<SpeakerCommand>) {
-    while let Err(err) = run_respeaker(&mut command_receiver) {
+fn respeaker_loop(
+    mut command_receiver: Receiver<SpeakerCommand>,
+    direction: Arc<AtomicI32>,
+    metrics: Arc<Metrics>,
+) {
+    while let Err(err) = run_respeaker(&mut command_receiver, &direction) {
+        // don't report direction of a device we lost
+        direction.store(NO_DIRECTION, Ordering::Relaxed);
         error!("ReSpeaker loop failed with err: {:?}", err);
+        metrics.increment(&RESPEAKER_ERRORS, &[]);
         thread::sleep(Duration::from_secs(1));
     }
     info!("Exiting ReSpeaker loop");
 }
 
-fn run_respeaker(command_receiver: &mut Receiver<SpeakerCommand>) -> Result<()> {
+fn run_respeaker(
+    command_receiver: &mut Receiver<SpeakerCommand>,
+    direction: &AtomicI32,
+) -> Result<()> {
     if let Some(mut pixel_ring) = find_usb_device(VENDOR_ID, PRODUCE_ID)? {
         info!("Found ReSpeaker USB device. Starting loop");
 
@@ -259,16 +295,20 @@ fn run_respeaker(command_receiver: &mut Receiver<SpeakerCommand>) -> Result<()>
         // pixel_ring.set_color_palette(BRIGHT_PATTERN_COLOR, DARK_PATTERN_COLOR)?;
         pixel_ring.off()?;
 
-        while let Ok(message) = command_receiver.recv() {
-            match message {
-                SpeakerCommand::Off => pixel_ring.off()?,
-                SpeakerCommand::Listen => pixel_ring.listen()?,
-                SpeakerCommand::Think => pixel_ring.think()?,
-                SpeakerCommand::ReadDirection(response_sender) => {
-                    let direction = pixel_ring.read_direction()?;
-                    // ignore error here because we don't care if caller is still alive
-                    _ = response_sender.send(direction);
-                }
+        // direction is polled here so that the audio thread never waits on USB
+        let mut next_direction_read = Instant::now();
+        loop {
+            if Instant::now() >= next_direction_read {
+                direction.store(pixel_ring.read_direction()?, Ordering::Relaxed);
+                next_direction_read = Instant::now() + READ_DIRECTION_INTERVAL;
+            }
+            match command_receiver.recv_timeout(READ_DIRECTION_INTERVAL) {
+                Ok(SpeakerCommand::Off) => pixel_ring.off()?,
+                Ok(SpeakerCommand::Listen) => pixel_ring.listen()?,
+                Ok(SpeakerCommand::Think) => pixel_ring.think()?,
+                Ok(SpeakerCommand::Speak) => pixel_ring.speak()?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
             }
         }
         pixel_ring.close()?;
@@ -0,0 +1,196 @@
+//! Processing time of individual stages of the audio frame pipeline
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+pub const STAGE_VALIDATION_BUFFER: &str = "validation_buffer";
+pub const STAGE_PORCUPINE: &str = "porcupine";
+pub const STAGE_COBRA: &str = "cobra";
+/// Whole frame excluding waiting for audio
+pub const STAGE_FRAME: &str = "frame";
+
+/// Upper bounds of histogram buckets in microseconds
+/// 512 samples at 16kHz give a 32ms frame budget
+const BUCKET_BOUNDS_US: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 16_000, 32_000, 64_000,
+];
+
+/// How often listener sends collected timings
+pub const STAGE_TIMINGS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Inclusive upper bounds of buckets in microseconds
+    pub bucket_bounds_us: Vec<u64>,
+    /// Count per bucket with one extra bucket for slower samples
+    pub bucket_counts: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+    pub max_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            bucket_bounds_us: BUCKET_BOUNDS_US.to_vec(),
+            bucket_counts: vec![0; BUCKET_BOUNDS_US.len() + 1],
+            count: 0,
+            sum_us: 0,
+            max_us: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let duration_us = duration.as_micros() as u64;
+        let bucket = self
+            .bucket_bounds_us
+            .iter()
+            .position(|bound| duration_us <= *bound)
+            .unwrap_or(self.bucket_bounds_us.len());
+        self.bucket_counts[bucket] += 1;
+        self.count += 1;
+        self.sum_us += duration_us;
+        self.max_us = self.max_us.max(duration_us);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (count, other_count) in self.bucket_counts.iter_mut().zip(&other.bucket_counts) {
+            *count += other_count;
+        }
+        self.count += other.count;
+        self.sum_us += other.sum_us;
+        self.max_us = self.max_us.max(other.max_us);
+    }
+
+    /// Upper bound of the bucket containing the percentile between 0 and 1
+    pub fn percentile(&self, percentile: f32) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile * self.count as f32).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (index, count) in self.bucket_counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                let bound_us = self
+                    .bucket_bounds_us
+                    .get(index)
+                    .copied()
+                    .unwrap_or(self.max_us)
+                    .min(self.max_us);
+                return Some(Duration::from_micros(bound_us));
+            }
+        }
+        Some(Duration::from_micros(self.max_us))
+    }
+}
+
+/// Collects stage histograms in the listener and hands them over periodically
+pub struct StageTimer {
+    histograms: HashMap<String, LatencyHistogram>,
+    last_flush: Instant,
+}
+
+impl StageTimer {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            histograms: HashMap::new(),
+            last_flush: now,
+        }
+    }
+
+    pub fn record(&mut self, stage: &str, duration: Duration) {
+        self.histograms
+            .entry(stage.to_owned())
+            .or_default()
+            .record(duration);
+    }
+
+    /// Histograms collected since last flush once interval elapsed
+    pub fn flush(&mut self, now: Instant) -> Option<HashMap<String, LatencyHistogram>> {
+        if now.duration_since(self.last_flush) < STAGE_TIMINGS_INTERVAL {
+            return None;
+        }
+        self.last_flush = now;
+        Some(std::mem::take(&mut self.histograms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(800));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_micros(12_000));
+        }
+        assert_eq!(histogram.count, 100);
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.percentile(0.9), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_millis(12)));
+
+        // slower than last bound reports max
+        histogram.record(Duration::from_millis(100));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn bucket_bounds_are_inclusive() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_micros(101));
+        histogram.record(Duration::from_micros(64_001));
+        assert_eq!(histogram.bucket_counts[0], 1);
+        assert_eq!(histogram.bucket_counts[1], 1);
+        assert_eq!(histogram.bucket_counts[BUCKET_BOUNDS_US.len()], 1);
+    }
+
+    #[test]
+    fn percentile_never_exceeds_max() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(150));
+        // bucket bound is 250us but nothing took that long
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_micros(150)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_micros(150)));
+    }
+
+    #[test]
+    fn histogram_merge() {
+        let mut first = LatencyHistogram::default();
+        first.record(Duration::from_micros(50));
+        let mut second = LatencyHistogram::default();
+        second.record(Duration::from_micros(3_000));
+        first.merge(&second);
+        assert_eq!(first.count, 2);
+        assert_eq!(first.sum_us, 3_050);
+        assert_eq!(first.max_us, 3_000);
+        assert_eq!(first.bucket_counts[0], 1);
+        assert_eq!(first.bucket_counts[5], 1);
+    }
+
+    #[test]
+    fn timer_flush_interval() {
+        let start = Instant::now();
+        let mut timer = StageTimer::new(start);
+        timer.record(STAGE_PORCUPINE, Duration::from_micros(900));
+        assert!(timer.flush(start + Duration::from_secs(1)).is_none());
+        let histograms = timer.flush(start + STAGE_TIMINGS_INTERVAL).unwrap();
+        assert_eq!(histograms[STAGE_PORCUPINE].count, 1);
+        // emptied after flush
+        let histograms = timer
+            .flush(start + STAGE_TIMINGS_INTERVAL + STAGE_TIMINGS_INTERVAL)
+            .unwrap();
+        assert!(histograms.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+use crate::{configuration::SystemPromptConfig, messages::PromptContextCommand};
+
+/// Vocabulary published by downstream nodes keyed by source
+pub type PromptContext = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+pub fn update_prompt_context(context: &PromptContext, command: PromptContextCommand) {
+    match context.lock() {
+        Ok(mut context) => {
+            if command.vocabulary.is_empty() {
+                context.remove(&command.source);
+            } else {
+                context.insert(command.source, command.vocabulary);
+            }
+        }
+        Err(_) => warn!("Prompt context lock poisoned"),
+    }
+}
+
+/// Renders system prompt templates for transcription requests
+///
+/// Supported placeholders are `${time}`, `${robot_name}`, `${wake_word}`,
+/// `${recent_transcripts}` and `${vocabulary}`
+pub struct SystemPrompts {
+    prompts: HashMap<String, String>,
+    config: SystemPromptConfig,
+    recent_transcripts: VecDeque<String>,
+    context: PromptContext,
+}
+
+impl SystemPrompts {
+    pub fn new(
+        prompts: HashMap<String, String>,
+        config: SystemPromptConfig,
+        context: PromptContext,
+    ) -> Self {
+        Self {
+            prompts,
+            config,
+            recent_transcripts: VecDeque::new(),
+            context,
+        }
+    }
+
+    /// Remember transcript for `${recent_transcripts}`
+    pub fn add_transcript(&mut self, transcript: &str) {
+        if self.config.recent_transcripts == 0 {
+            return;
+        }
+        while self.recent_transcripts.len() >= self.config.recent_transcripts {
+            self.recent_transcripts.pop_front();
+        }
+        self.recent_transcripts
+            .push_back(transcript.trim().to_owned());
+    }
+
+    pub fn render(&self, wake_word: &str, now: chrono::DateTime<chrono::Local>) -> String {
+        let template = match self
+            .prompts
+            .get(wake_word)
+            .or(self.config.default_prompt.as_ref())
+        {
+            Some(template) => template,
+            None => return String::new(),
+        };
+
+        let mut vocabulary = self.config.vocabulary.clone();
+        match self.context.lock() {
+            Ok(context) => {
+                let mut sources: Vec<_> = context.iter().collect();
+                // keep prompt stable between requests
+                sources.sort_by_key(|(source, _)| source.as_str());
+                for (_, words) in sources {
+                    vocabulary.extend(words.iter().cloned());
+                }
+            }
+            Err(_) => warn!("Prompt context lock poisoned"),
+        }
+
+        let recent_transcripts: Vec<&str> =
+            self.recent_transcripts.iter().map(String::as_str).collect();
+
+        // single pass so that placeholders inside transcripts or vocabulary stay literal
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find("${") {
+            rendered.push_str(&rest[..start]);
+            let placeholder = &rest[start..];
+            let Some(end) = placeholder.find('}') else {
+                rest = placeholder;
+                break;
+            };
+            let value = match &placeholder[2..end] {
+                "time" => now.format("%A %H:%M").to_string(),
+                "robot_name" => self.config.robot_name.clone(),
+                "wake_word" => wake_word.to_owned(),
+                "recent_transcripts" => recent_transcripts.join(" "),
+                "vocabulary" => vocabulary.join(", "),
+                // unknown placeholders are kept so that typos are visible in the prompt
+                _ => placeholder[..=end].to_owned(),
+            };
+            rendered.push_str(&value);
+            rest = &placeholder[end + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn friday_evening() -> chrono::DateTime<chrono::Local> {
+        chrono::Local
+            .with_ymd_and_hms(2024, 5, 3, 18, 30, 0)
+            .unwrap()
+    }
+
+    fn context_command(source: &str, vocabulary: &[&str]) -> PromptContextCommand {
+        PromptContextCommand {
+            source: source.to_owned(),
+            vocabulary: vocabulary.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        let prompts = SystemPrompts::new(
+            HashMap::from([(
+                String::from("Hey Hopper"),
+                String::from("${robot_name} heard ${wake_word} on ${time}. ${vocabulary}"),
+            )]),
+            SystemPromptConfig {
+                robot_name: String::from("Hopper"),
+                vocabulary: vec![String::from("larson scanner")],
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        assert_eq!(
+            prompts.render("Hey Hopper", friday_evening()),
+            "Hopper heard Hey Hopper on Friday 18:30. larson scanner"
+        );
+    }
+
+    #[test]
+    fn missing_prompt_without_default_is_empty() {
+        let prompts = SystemPrompts::new(
+            HashMap::from([(String::from("Hey Hopper"), String::from("${robot_name}"))]),
+            SystemPromptConfig::default(),
+            Default::default(),
+        );
+        assert_eq!(prompts.render("Wintermute", friday_evening()), "");
+    }
+
+    #[test]
+    fn unknown_and_unterminated_placeholders_stay_literal() {
+        let prompts = SystemPrompts::new(
+            HashMap::new(),
+            SystemPromptConfig {
+                default_prompt: Some(String::from("${robot} ${wake_word} $ {x} ${wake_word")),
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        assert_eq!(
+            prompts.render("Songbird", friday_evening()),
+            "${robot} Songbird $ {x} ${wake_word"
+        );
+    }
+
+    #[test]
+    fn transcripts_containing_placeholders_are_not_expanded() {
+        let mut prompts = SystemPrompts::new(
+            HashMap::new(),
+            SystemPromptConfig {
+                default_prompt: Some(String::from("${recent_transcripts} | ${vocabulary}")),
+                vocabulary: vec![String::from("${robot_name}")],
+                robot_name: String::from("Hopper"),
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        prompts.add_transcript("Say ${vocabulary}");
+        assert_eq!(
+            prompts.render("Songbird", friday_evening()),
+            "Say ${vocabulary} | ${robot_name}"
+        );
+    }
+
+    #[test]
+    fn recent_transcripts_are_bounded_and_trimmed() {
+        let mut prompts = SystemPrompts::new(
+            HashMap::new(),
+            SystemPromptConfig {
+                default_prompt: Some(String::from("Previously: ${recent_transcripts}")),
+                recent_transcripts: 2,
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        prompts.add_transcript("Sit down.");
+        prompts.add_transcript("Stand up.");
+        prompts.add_transcript(" Dance. ");
+        assert_eq!(
+            prompts.render("Wintermute", friday_evening()),
+            "Previously: Stand up. Dance."
+        );
+
+        prompts.config.recent_transcripts = 0;
+        prompts.recent_transcripts.clear();
+        prompts.add_transcript("Sit down.");
+        assert_eq!(
+            prompts.render("Wintermute", friday_evening()),
+            "Previously: "
+        );
+    }
+
+    #[test]
+    fn context_vocabulary_is_sorted_by_source_and_cleared_when_empty() {
+        let prompts = SystemPrompts::new(
+            HashMap::new(),
+            SystemPromptConfig {
+                default_prompt: Some(String::from("${vocabulary}")),
+                vocabulary: vec![String::from("larson scanner")],
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        update_prompt_context(
+            &prompts.context,
+            context_command("object_detection", &["cup"]),
+        );
+        update_prompt_context(
+            &prompts.context,
+            context_command("face_recognition", &["David"]),
+        );
+        assert_eq!(
+            prompts.render("Hey Hopper", friday_evening()),
+            "larson scanner, David, cup"
+        );
+
+        // replaced vocabulary doesn't accumulate
+        update_prompt_context(
+            &prompts.context,
+            context_command("face_recognition", &["Alice"]),
+        );
+        update_prompt_context(&prompts.context, context_command("object_detection", &[]));
+        assert_eq!(
+            prompts.render("Hey Hopper", friday_evening()),
+            "larson scanner, Alice"
+        );
+    }
+}
@@ -0,0 +1,441 @@
+use anyhow::Context;
+use realfft::RealFftPlanner;
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use tracing::info;
+
+use crate::{
+    audio_encoding::{read_wav, wav_files},
+    wakeword_validation::{
+        ValidationBackend, ValidationOutcome, ValidationReceiver, ValidationRequest,
+    },
+};
+
+const FRAME_LENGTH: Duration = Duration::from_millis(25);
+const FRAME_STEP: Duration = Duration::from_millis(10);
+const PRE_EMPHASIS: f32 = 0.97;
+const MEL_FILTERS: usize = 26;
+/// First coefficient is dropped because it mostly tracks loudness
+const MFCC_COEFFICIENTS: usize = 13;
+/// Frames quieter than the loudest frame by this much log energy are trimmed from templates
+const SILENCE_LOG_ENERGY_RANGE: f32 = 5.0;
+
+/// Computes MFCC features for fixed sample rate
+pub struct MfccExtractor {
+    sample_rate: u32,
+    frame_length: usize,
+    frame_step: usize,
+    fft_length: usize,
+    window: Vec<f32>,
+    mel_filters: Vec<Vec<f32>>,
+}
+
+impl MfccExtractor {
+    pub fn new(sample_rate: u32) -> Self {
+        let frame_length = (sample_rate as f32 * FRAME_LENGTH.as_secs_f32()) as usize;
+        let frame_step = (sample_rate as f32 * FRAME_STEP.as_secs_f32()) as usize;
+        let fft_length = frame_length.next_power_of_two();
+        let window = (0..frame_length)
+            .map(|i| {
+                0.54 - 0.46
+                    * (2.0 * std::f32::consts::PI * i as f32 / (frame_length - 1) as f32).cos()
+            })
+            .collect();
+        let mel_filters = mel_filter_bank(sample_rate, fft_length);
+        Self {
+            sample_rate,
+            frame_length,
+            frame_step,
+            fft_length,
+            window,
+            mel_filters,
+        }
+    }
+
+    /// MFCC frames with cepstral mean normalization
+    pub fn extract(&self, samples: &[i16]) -> Vec<Vec<f32>> {
+        if samples.len() < self.frame_length {
+            return vec![];
+        }
+        let mut planner = RealFftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(self.fft_length);
+        let mut input = fft.make_input_vec();
+        let mut spectrum = fft.make_output_vec();
+
+        let emphasized: Vec<f32> = std::iter::once(samples[0] as f32)
+            .chain(
+                samples
+                    .windows(2)
+                    .map(|pair| pair[1] as f32 - PRE_EMPHASIS * pair[0] as f32),
+            )
+            .map(|sample| sample / i16::MAX as f32)
+            .collect();
+
+        let mut frames = vec![];
+        for start in (0..=emphasized.len() - self.frame_length).step_by(self.frame_step) {
+            input.iter_mut().for_each(|value| *value = 0.0);
+            for (i, sample) in emphasized[start..start + self.frame_length]
+                .iter()
+                .enumerate()
+            {
+                input[i] = sample * self.window[i];
+            }
+            // buffers have correct lengths so this can't fail
+            fft.process(&mut input, &mut spectrum)
+                .expect("FFT buffers have planned lengths");
+            let power: Vec<f32> = spectrum.iter().map(|bin| bin.norm_sqr()).collect();
+
+            let log_energies: Vec<f32> = self
+                .mel_filters
+                .iter()
+                .map(|filter| {
+                    let energy: f32 = filter.iter().zip(&power).map(|(f, p)| f * p).sum();
+                    energy.max(f32::EPSILON).ln()
+                })
+                .collect();
+            frames.push(dct(&log_energies, MFCC_COEFFICIENTS));
+        }
+
+        normalize_cepstral_mean(&mut frames);
+        frames
+    }
+
+    /// Features of enrollment sample trimmed of leading and trailing silence
+    pub fn extract_template(&self, samples: &[i16]) -> Vec<Vec<f32>> {
+        let energies: Vec<f32> = samples
+            .chunks(self.frame_step)
+            .map(|chunk| {
+                let energy: f32 = chunk.iter().map(|s| (*s as f32).powi(2)).sum();
+                (energy / chunk.len() as f32).max(1.0).ln()
+            })
+            .collect();
+        let max_energy = energies.iter().copied().fold(f32::MIN, f32::max);
+        let is_loud = |energy: &f32| *energy > max_energy - SILENCE_LOG_ENERGY_RANGE;
+        let start = energies.iter().position(is_loud).unwrap_or(0) * self.frame_step;
+        let end = (energies.iter().rposition(is_loud).unwrap_or(0) + 1) * self.frame_step;
+        self.extract(&samples[start..end.min(samples.len())])
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Triangular filters over FFT bins spaced evenly on mel scale
+fn mel_filter_bank(sample_rate: u32, fft_length: usize) -> Vec<Vec<f32>> {
+    let bins = fft_length / 2 + 1;
+    let max_mel = hz_to_mel(sample_rate as f32 / 2.0);
+    let centers: Vec<f32> = (0..MEL_FILTERS + 2)
+        .map(|i| mel_to_hz(max_mel * i as f32 / (MEL_FILTERS + 1) as f32))
+        .map(|hz| hz * fft_length as f32 / sample_rate as f32)
+        .collect();
+    (1..=MEL_FILTERS)
+        .map(|filter| {
+            let (left, center, right) = (centers[filter - 1], centers[filter], centers[filter + 1]);
+            (0..bins)
+                .map(|bin| {
+                    let bin = bin as f32;
+                    if bin > left && bin <= center {
+                        (bin - left) / (center - left)
+                    } else if bin > center && bin < right {
+                        (right - bin) / (right - center)
+                    } else {
+                        0.0
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// DCT-II skipping the first coefficient
+fn dct(values: &[f32], coefficients: usize) -> Vec<f32> {
+    let n = values.len() as f32;
+    (1..coefficients)
+        .map(|k| {
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    value * (std::f32::consts::PI * k as f32 * (i as f32 + 0.5) / n).cos()
+                })
+                .sum()
+        })
+        .collect()
+}
+
+fn normalize_cepstral_mean(frames: &mut [Vec<f32>]) {
+    let Some(dimensions) = frames.first().map(Vec::len) else {
+        return;
+    };
+    for dimension in 0..dimensions {
+        let mean = frames.iter().map(|frame| frame[dimension]).sum::<f32>() / frames.len() as f32;
+        frames.iter_mut().for_each(|frame| frame[dimension] -= mean);
+    }
+}
+
+fn frame_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Subsequence DTW. Template can start and end anywhere in the query
+///
+/// Returns distance averaged over template frames
+pub fn subsequence_dtw_distance(template: &[Vec<f32>], query: &[Vec<f32>]) -> f32 {
+    if template.is_empty() || query.is_empty() {
+        return f32::INFINITY;
+    }
+    // free start anywhere in query
+    let mut previous = vec![0.0f32; query.len()];
+    let mut current = vec![0.0; query.len()];
+    for template_frame in template {
+        for (j, query_frame) in query.iter().enumerate() {
+            let cost = frame_distance(template_frame, query_frame);
+            let best_previous = if j == 0 {
+                previous[0]
+            } else {
+                previous[j].min(previous[j - 1]).min(current[j - 1])
+            };
+            current[j] = cost + best_previous;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous.iter().copied().fold(f32::INFINITY, f32::min) / template.len() as f32
+}
+
+/// Offline verifier comparing audio against enrolled samples of each wake word
+pub struct TemplateValidator {
+    extractor: Arc<MfccExtractor>,
+    templates: Arc<HashMap<String, Vec<Vec<Vec<f32>>>>>,
+    score_threshold: f32,
+}
+
+impl TemplateValidator {
+    /// Loads `<enrollment_dir>/<wake word>/*.wav` recorded at the microphone sample rate
+    ///
+    /// Each of `validated_keywords` needs at least one sample
+    pub fn from_directory(
+        enrollment_dir: &Path,
+        validated_keywords: &[String],
+        sample_rate: u32,
+        score_threshold: f32,
+    ) -> anyhow::Result<Self> {
+        let extractor = MfccExtractor::new(sample_rate);
+        let mut templates = HashMap::new();
+        for keyword_dir in std::fs::read_dir(enrollment_dir)
+            .with_context(|| format!("Failed to read enrollment dir {:?}", enrollment_dir))?
+        {
+            let keyword_dir = keyword_dir?.path();
+            if !keyword_dir.is_dir() {
+                continue;
+            }
+            let wake_word = keyword_dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("Invalid wake word directory name")?
+                // runtime wake words are the lowercased config keys
+                .to_lowercase();
+            let mut keyword_templates = vec![];
+            for file in wav_files(&keyword_dir)? {
+                let samples = read_wav(&file, sample_rate)?;
+                keyword_templates.push(extractor.extract_template(&samples));
+            }
+            info!(
+                "Enrolled {} samples for {:?}",
+                keyword_templates.len(),
+                wake_word
+            );
+            templates.insert(wake_word, keyword_templates);
+        }
+        for keyword in validated_keywords {
+            if templates.get(keyword).map_or(true, Vec::is_empty) {
+                anyhow::bail!(
+                    "No enrolled samples for validated keyword {:?} in {:?}",
+                    keyword,
+                    enrollment_dir
+                );
+            }
+        }
+        Ok(Self {
+            extractor: Arc::new(extractor),
+            templates: Arc::new(templates),
+            score_threshold,
+        })
+    }
+
+    /// Similarity `1 / (1 + distance)` between 0 and 1 of best matching template
+    ///
+    /// Distance is the per frame average DTW distance so identical audio scores 1
+    fn score(extractor: &MfccExtractor, templates: &[Vec<Vec<f32>>], samples: &[i16]) -> f32 {
+        let query = extractor.extract(samples);
+        let distance = templates
+            .iter()
+            .map(|template| subsequence_dtw_distance(template, &query))
+            .fold(f32::INFINITY, f32::min);
+        1.0 / (1.0 + distance)
+    }
+}
+
+impl ValidationBackend for TemplateValidator {
+    fn validate(&self, request: ValidationRequest) -> anyhow::Result<ValidationReceiver> {
+        if request.sample_rate != self.extractor.sample_rate {
+            anyhow::bail!("Audio sample rate doesn't match enrollment sample rate");
+        }
+        let templates = self.templates.clone();
+        let extractor = self.extractor.clone();
+        let score_threshold = self.score_threshold;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::task::spawn_blocking(move || {
+            let result = match templates.get(&request.wake_word) {
+                Some(keyword_templates) if !keyword_templates.is_empty() => {
+                    let score =
+                        TemplateValidator::score(&extractor, keyword_templates, &request.samples);
+                    info!(
+                        "Wakeword {:?} template score {:.3}",
+                        request.wake_word, score
+                    );
+                    Ok(ValidationOutcome {
+                        valid: score >= score_threshold,
+                        score,
+                        transcript: None,
+                    })
+                }
+                _ => Err(anyhow::anyhow!(
+                    "No enrolled samples for {:?}",
+                    request.wake_word
+                )),
+            };
+            _ = tx.send(result);
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    /// Sequence of tones standing in for a spoken word
+    fn tones(frequencies: &[f32], tone_length: usize) -> Vec<i16> {
+        frequencies
+            .iter()
+            .flat_map(|frequency| {
+                (0..tone_length).map(move |i| {
+                    let t = i as f32 / SAMPLE_RATE as f32;
+                    ((2.0 * std::f32::consts::PI * frequency * t).sin() * 8000.0) as i16
+                })
+            })
+            .collect()
+    }
+
+    fn silence(length: usize) -> Vec<i16> {
+        vec![0; length]
+    }
+
+    #[test]
+    fn mfcc_frame_count() {
+        let extractor = MfccExtractor::new(SAMPLE_RATE);
+        // one second gives frames every 10ms with 25ms window
+        let frames = extractor.extract(&silence(SAMPLE_RATE as usize));
+        assert_eq!(frames.len(), 98);
+        assert_eq!(frames[0].len(), MFCC_COEFFICIENTS - 1);
+        assert!(extractor.extract(&silence(10)).is_empty());
+    }
+
+    #[test]
+    fn dtw_identical_is_zero() {
+        let extractor = MfccExtractor::new(SAMPLE_RATE);
+        let features = extractor.extract(&tones(&[300.0, 1200.0, 600.0], 4000));
+        assert_eq!(subsequence_dtw_distance(&features, &features), 0.0);
+        assert_eq!(subsequence_dtw_distance(&[], &features), f32::INFINITY);
+    }
+
+    #[test]
+    fn template_found_inside_longer_audio() {
+        let extractor = MfccExtractor::new(SAMPLE_RATE);
+        let word = tones(&[300.0, 1200.0, 600.0], 4000);
+        let other_word = tones(&[2500.0, 400.0, 3200.0], 4000);
+        let template = extractor.extract_template(&[silence(3000), word.clone()].concat());
+
+        let matching = [silence(8000), word, silence(16000)].concat();
+        let not_matching = [silence(8000), other_word, silence(16000)].concat();
+        let templates = vec![template];
+
+        let matching_score = TemplateValidator::score(&extractor, &templates, &matching);
+        let not_matching_score = TemplateValidator::score(&extractor, &templates, &not_matching);
+        assert!(
+            matching_score > not_matching_score,
+            "{} <= {}",
+            matching_score,
+            not_matching_score
+        );
+    }
+
+    #[test]
+    fn template_trims_silence() {
+        let extractor = MfccExtractor::new(SAMPLE_RATE);
+        let word = tones(&[300.0, 1200.0], 4000);
+        let padded = [silence(8000), word.clone(), silence(8000)].concat();
+        let template = extractor.extract_template(&padded);
+        assert!(template.len() < extractor.extract(&padded).len());
+        assert!(template.len() >= extractor.extract(&word).len() - 1);
+    }
+
+    #[test]
+    fn enrollment_from_directory() {
+        let enrollment_dir =
+            std::env::temp_dir().join(format!("wakeword_enrollment_{}", uuid::Uuid::new_v4()));
+        let keyword_dir = enrollment_dir.join("Hey Hopper");
+        std::fs::create_dir_all(&keyword_dir).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(keyword_dir.join("1.wav"), spec).unwrap();
+        for sample in tones(&[300.0, 1200.0], 4000) {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        // detections carry the lowercased config key
+        let validated_keywords = vec![String::from("hey hopper")];
+        let validator = TemplateValidator::from_directory(
+            &enrollment_dir,
+            &validated_keywords,
+            SAMPLE_RATE,
+            0.5,
+        )
+        .unwrap();
+        assert_eq!(validator.templates["hey hopper"].len(), 1);
+        assert!(
+            TemplateValidator::from_directory(&enrollment_dir, &validated_keywords, 8000, 0.5)
+                .is_err()
+        );
+
+        // keyword without enrollment would fail every validation
+        std::fs::create_dir_all(enrollment_dir.join("Songbird")).unwrap();
+        let err = TemplateValidator::from_directory(
+            &enrollment_dir,
+            &[String::from("hey hopper"), String::from("songbird")],
+            SAMPLE_RATE,
+            0.5,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("songbird"));
+
+        std::fs::remove_dir_all(&enrollment_dir).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use anyhow::Context;
+use regex::Regex;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    configuration::TranscriptFilterConfig,
+    messages::{DismissRule, TranscriptFilterReason},
+};
+
+struct WakeWordRules {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+}
+
+/// Chain of checks rejecting transcripts that are likely Whisper hallucinations or junk
+pub struct TranscriptFilter {
+    blocklist: Vec<String>,
+    min_word_count: usize,
+    max_repeated_words: Option<usize>,
+    max_characters_per_second: Option<f32>,
+    wake_word_rules: HashMap<String, WakeWordRules>,
+}
+
+impl TranscriptFilter {
+    pub fn new(config: &TranscriptFilterConfig) -> anyhow::Result<Self> {
+        let mut wake_word_rules = HashMap::new();
+        for (wake_word, rules) in &config.wake_words {
+            let allow = compile_patterns(&rules.allow)
+                .with_context(|| format!("Invalid allow pattern for {:?}", wake_word))?;
+            let deny = compile_patterns(&rules.deny)
+                .with_context(|| format!("Invalid deny pattern for {:?}", wake_word))?;
+            wake_word_rules.insert(wake_word.clone(), WakeWordRules { allow, deny });
+        }
+
+        Ok(Self {
+            blocklist: config
+                .blocklist
+                .iter()
+                .map(|phrase| normalize_transcript(phrase))
+                .collect(),
+            min_word_count: config.min_word_count,
+            max_repeated_words: config.max_repeated_words,
+            max_characters_per_second: config.max_characters_per_second,
+            wake_word_rules,
+        })
+    }
+
+    /// Returns reason if transcript should be filtered out
+    pub fn check(
+        &self,
+        wake_word: &str,
+        transcript: &str,
+        audio_duration: Duration,
+    ) -> Option<TranscriptFilterReason> {
+        let normalized = normalize_transcript(transcript);
+
+        if let Some(phrase) = self.blocklist.iter().find(|phrase| **phrase == normalized) {
+            return Some(TranscriptFilterReason::Blocklisted {
+                phrase: phrase.clone(),
+            });
+        }
+
+        let words: Vec<&str> = normalized.split_whitespace().collect();
+        if words.len() < self.min_word_count {
+            return Some(TranscriptFilterReason::TooFewWords {
+                word_count: words.len(),
+            });
+        }
+
+        if let Some(max_repeated_words) = self.max_repeated_words {
+            if let Some((phrase, repeats)) = longest_repeated_phrase(&words) {
+                if repeats > max_repeated_words {
+                    return Some(if phrase.len() == 1 {
+                        TranscriptFilterReason::RepeatedWord {
+                            word: phrase[0].to_owned(),
+                            repeats,
+                        }
+                    } else {
+                        TranscriptFilterReason::RepeatedPhrase {
+                            phrase: phrase.join(" "),
+                            repeats,
+                        }
+                    });
+                }
+            }
+        }
+
+        if let Some(max_characters_per_second) = self.max_characters_per_second {
+            let characters_per_second =
+                normalized.chars().count() as f32 / audio_duration.as_secs_f32().max(f32::EPSILON);
+            if characters_per_second > max_characters_per_second {
+                return Some(TranscriptFilterReason::TooManyCharactersPerSecond {
+                    characters_per_second,
+                });
+            }
+        }
+
+        if let Some(rules) = self.wake_word_rules.get(wake_word) {
+            if let Some(pattern) = rules
+                .deny
+                .iter()
+                .find(|pattern| pattern.is_match(transcript))
+            {
+                return Some(TranscriptFilterReason::Denied {
+                    pattern: pattern.to_string(),
+                });
+            }
+            if !rules.allow.is_empty()
+                && !rules
+                    .allow
+                    .iter()
+                    .any(|pattern| pattern.is_match(transcript))
+            {
+                return Some(TranscriptFilterReason::NotAllowed);
+            }
+        }
+
+        None
+    }
+}
+
+/// Phrases that dismiss a recording when found in its transcript
+pub struct DismissPhrases {
+    phrases: Vec<(String, Regex)>,
+}
+
+impl DismissPhrases {
+    pub fn new(phrases: &[String]) -> anyhow::Result<Self> {
+        let phrases = phrases
+            .iter()
+            .map(|phrase| {
+                phrase_regex(phrase)
+                    .with_context(|| format!("Bad dismiss phrase {:?}", phrase))
+                    .map(|regex| (phrase.clone(), regex))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { phrases })
+    }
+
+    /// Returns the rule that matched if the transcript should be dismissed
+    pub fn check(&self, transcript: &str) -> Option<DismissRule> {
+        self.phrases
+            .iter()
+            .find(|(_, regex)| regex.is_match(transcript))
+            .map(|(phrase, _)| DismissRule::Phrase {
+                phrase: phrase.clone(),
+            })
+    }
+}
+
+/// Case insensitive regex matching phrase on word boundaries
+///
+/// Boundaries are checked outside the phrase so that phrases can start or end with punctuation
+pub fn phrase_regex(phrase: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!(
+        r"(?i)(?:^|\W){}(?:\W|$)",
+        regex::escape(phrase.trim())
+    ))
+}
+
+fn compile_patterns(patterns: &[String]) -> anyhow::Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("Bad pattern {:?}", pattern)))
+        .collect()
+}
+
+/// Lowercase, strip punctuation and collapse whitespace
+pub fn normalize_transcript(transcript: &str) -> String {
+    transcript
+        .chars()
+        .map(|character| {
+            if character.is_alphanumeric() || character == '\'' {
+                character
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Phrase with the longest run of back to back repeats
+///
+/// Phrases of any length are checked so that "thank you thank you" counts as 2 repeats.
+/// On equal repeats the shorter phrase wins
+fn longest_repeated_phrase<'a, 'b>(words: &'b [&'a str]) -> Option<(&'b [&'a str], usize)> {
+    let mut longest: Option<(&[&str], usize)> = None;
+    for phrase_length in 1..=(words.len() / 2).max(1) {
+        for start in 0..words.len() {
+            let Some(phrase) = words.get(start..start + phrase_length) else {
+                break;
+            };
+            let repeats = words[start..]
+                .chunks_exact(phrase_length)
+                .take_while(|chunk| *chunk == phrase)
+                .count();
+            if longest.map_or(true, |(_, longest_repeats)| repeats > longest_repeats) {
+                longest = Some((phrase, repeats));
+            }
+        }
+    }
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::WakeWordTranscriptFilterConfig;
+
+    const DURATION: Duration = Duration::from_secs(5);
+
+    fn repeat_filter(max_repeated_words: usize) -> TranscriptFilter {
+        TranscriptFilter::new(&TranscriptFilterConfig {
+            max_repeated_words: Some(max_repeated_words),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn dismiss_phrases() {
+        let dismiss_phrases =
+            DismissPhrases::new(&[String::from("never mind"), String::from("dismiss")]).unwrap();
+        assert!(matches!(
+            dismiss_phrases.check("Oh, Never mind."),
+            Some(DismissRule::Phrase { phrase }) if phrase == "never mind"
+        ));
+        assert!(dismiss_phrases.check("Dismiss!").is_some());
+        // partial words don't match
+        assert!(dismiss_phrases.check("That was dismissive").is_none());
+        assert!(dismiss_phrases.check("Sit down").is_none());
+    }
+
+    #[test]
+    fn dismiss_phrase_ending_in_punctuation() {
+        let dismiss_phrases = DismissPhrases::new(&[String::from(" stop (now) ")]).unwrap();
+        assert!(dismiss_phrases.check("Please STOP (now)").is_some());
+        assert!(dismiss_phrases.check("stop (now)!").is_some());
+        assert!(dismiss_phrases.check("stop now").is_none());
+        assert!(dismiss_phrases.check("stop (now)ish").is_none());
+    }
+
+    #[test]
+    fn normalization() {
+        assert_eq!(
+            normalize_transcript("  Thank you,  for WATCHING!! "),
+            "thank you for watching"
+        );
+        assert_eq!(normalize_transcript("Don't stop."), "don't stop");
+        assert_eq!(normalize_transcript(" ... "), "");
+    }
+
+    #[test]
+    fn blocklist_matches_whole_transcript_only() {
+        let filter = TranscriptFilter::new(&TranscriptFilterConfig {
+            blocklist: vec![String::from("Thank you for watching!")],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            filter.check("Hey Hopper", "thank you, for watching.", DURATION),
+            Some(TranscriptFilterReason::Blocklisted {
+                phrase: String::from("thank you for watching")
+            })
+        );
+        assert_eq!(
+            filter.check("Hey Hopper", "Thank you for watching the dog", DURATION),
+            None
+        );
+    }
+
+    #[test]
+    fn punctuation_only_transcript_has_no_words() {
+        let filter = TranscriptFilter::new(&TranscriptFilterConfig {
+            min_word_count: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            filter.check("Hey Hopper", " . ", DURATION),
+            Some(TranscriptFilterReason::TooFewWords { word_count: 0 })
+        );
+    }
+
+    #[test]
+    fn repeated_word_at_limit() {
+        let filter = repeat_filter(3);
+        assert_eq!(filter.check("Hey Hopper", "no no, no", DURATION), None);
+        assert_eq!(
+            filter.check("Hey Hopper", "you you, you you", DURATION),
+            Some(TranscriptFilterReason::RepeatedWord {
+                word: String::from("you"),
+                repeats: 4
+            })
+        );
+    }
+
+    #[test]
+    fn repeated_phrase() {
+        let filter = repeat_filter(2);
+        assert_eq!(
+            filter.check("Hey Hopper", "Thank you. Thank you. Thank you.", DURATION),
+            Some(TranscriptFilterReason::RepeatedPhrase {
+                phrase: String::from("thank you"),
+                repeats: 3
+            })
+        );
+        // repeats have to be back to back
+        assert_eq!(
+            filter.check(
+                "Hey Hopper",
+                "thank you so much thank you again thank you",
+                DURATION
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn repeated_phrase_after_prefix() {
+        assert_eq!(
+            longest_repeated_phrase(&["so", "go", "left", "go", "left", "go", "left"]),
+            Some((&["go", "left"][..], 3))
+        );
+        // shorter phrase wins on equal repeats
+        assert_eq!(
+            longest_repeated_phrase(&["ha", "ha", "ha", "ha"]),
+            Some((&["ha"][..], 4))
+        );
+        assert_eq!(longest_repeated_phrase(&["hi"]), Some((&["hi"][..], 1)));
+        assert_eq!(longest_repeated_phrase(&[]), None);
+    }
+
+    #[test]
+    fn zero_duration_recording() {
+        let filter = TranscriptFilter::new(&TranscriptFilterConfig {
+            max_characters_per_second: Some(30.0),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            filter.check("Hey Hopper", "hi", Duration::ZERO),
+            Some(TranscriptFilterReason::TooManyCharactersPerSecond { characters_per_second })
+                if characters_per_second.is_finite()
+        ));
+    }
+
+    #[test]
+    fn wake_word_rules() {
+        let mut wake_words = HashMap::new();
+        wake_words.insert(
+            String::from("Songbird"),
+            WakeWordTranscriptFilterConfig {
+                allow: vec![String::from("(?i)^play")],
+                deny: vec![String::from("(?i)subscribe")],
+            },
+        );
+        let filter = TranscriptFilter::new(&TranscriptFilterConfig {
+            wake_words,
+            ..Default::default()
+        })
+        .unwrap();
+        // deny wins over allow
+        assert_eq!(
+            filter.check("Songbird", "Play and subscribe", DURATION),
+            Some(TranscriptFilterReason::Denied {
+                pattern: String::from("(?i)subscribe")
+            })
+        );
+        assert_eq!(
+            filter.check("Songbird", "stop the music", DURATION),
+            Some(TranscriptFilterReason::NotAllowed)
+        );
+        assert_eq!(filter.check("Songbird", "Play some jazz", DURATION), None);
+        // rules don't apply to other wake words
+        assert_eq!(filter.check("Wintermute", "subscribe", DURATION), None);
+    }
+
+    #[test]
+    fn invalid_wake_word_pattern() {
+        let mut wake_words = HashMap::new();
+        wake_words.insert(
+            String::from("Songbird"),
+            WakeWordTranscriptFilterConfig {
+                allow: vec![String::from("(unclosed")],
+                deny: vec![],
+            },
+        );
+        let err = TranscriptFilter::new(&TranscriptFilterConfig {
+            wake_words,
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(format!("{:?}", err).contains("Songbird"));
+    }
+}
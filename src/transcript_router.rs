@@ -0,0 +1,236 @@
+use anyhow::Context;
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::{configuration::TranscriptRouteConfig, transcript_filter::phrase_regex};
+
+struct TranscriptRoute {
+    name: String,
+    topic: String,
+    wake_words: Vec<String>,
+    pattern: Option<Regex>,
+    keywords: Vec<Regex>,
+}
+
+impl TranscriptRoute {
+    /// Named capture groups if route matches
+    fn check(&self, wake_word: &str, transcript: &str) -> Option<HashMap<String, String>> {
+        if !self.wake_words.is_empty() && !self.wake_words.iter().any(|word| word == wake_word) {
+            return None;
+        }
+
+        // route without matchers accepts everything for its wake words
+        if self.pattern.is_none() && self.keywords.is_empty() {
+            return Some(HashMap::new());
+        }
+
+        if let Some(pattern) = &self.pattern {
+            if let Some(captures) = pattern.captures(transcript) {
+                let captures = pattern
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        captures
+                            .name(name)
+                            .map(|capture| (name.to_owned(), capture.as_str().to_owned()))
+                    })
+                    .collect();
+                return Some(captures);
+            }
+        }
+
+        if self
+            .keywords
+            .iter()
+            .any(|keyword| keyword.is_match(transcript))
+        {
+            return Some(HashMap::new());
+        }
+
+        None
+    }
+}
+
+pub struct RouteMatch<'a> {
+    pub name: &'a str,
+    pub topic: &'a str,
+    pub captures: HashMap<String, String>,
+}
+
+/// Routes transcripts to per intent topics
+pub struct TranscriptRouter {
+    routes: Vec<TranscriptRoute>,
+}
+
+impl TranscriptRouter {
+    pub fn new(routes: &[TranscriptRouteConfig]) -> anyhow::Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                let pattern = route
+                    .pattern
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .with_context(|| format!("Bad pattern for route {:?}", route.name))?;
+                let keywords = route
+                    .keywords
+                    .iter()
+                    .map(|keyword| phrase_regex(keyword))
+                    .collect::<Result<_, _>>()
+                    .with_context(|| format!("Bad keyword for route {:?}", route.name))?;
+                Ok(TranscriptRoute {
+                    name: route.name.clone(),
+                    topic: route.topic.clone(),
+                    // runtime wake words are the lowercased config keys
+                    wake_words: route
+                        .wake_words
+                        .iter()
+                        .map(|wake_word| wake_word.to_lowercase())
+                        .collect(),
+                    pattern,
+                    keywords,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { routes })
+    }
+
+    /// First route matching the transcript
+    pub fn route(&self, wake_word: &str, transcript: &str) -> Option<RouteMatch<'_>> {
+        self.routes.iter().find_map(|route| {
+            route
+                .check(wake_word, transcript)
+                .map(|captures| RouteMatch {
+                    name: &route.name,
+                    topic: &route.topic,
+                    captures,
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::WakewordConfig,
+        messages::{AudioTranscript, RoutedTranscript},
+    };
+
+    fn route(name: &str, pattern: Option<&str>, keywords: &[&str]) -> TranscriptRouteConfig {
+        TranscriptRouteConfig {
+            name: name.to_owned(),
+            topic: format!("hopper/command/{}", name),
+            wake_words: vec![],
+            pattern: pattern.map(str::to_owned),
+            keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn captures_colliding_with_transcript_fields() {
+        let router = TranscriptRouter::new(&[route(
+            "say",
+            Some(r"(?i)say (?P<transcript>.+?)(?: in (?P<wake_word>\w+))?$"),
+            &[],
+        )])
+        .unwrap();
+        let route_match = router.route("Hey Hopper", "Say hello").unwrap();
+        // optional group that didn't participate is left out
+        assert_eq!(route_match.captures.len(), 1);
+
+        let routed_transcript = RoutedTranscript {
+            route: route_match.name.to_owned(),
+            transcript: AudioTranscript {
+                recording_id: uuid::Uuid::nil(),
+                wake_word: String::from("Hey Hopper"),
+                timestamp: chrono::Utc::now(),
+                transcript: String::from("Say hello"),
+                language: None,
+                duration: None,
+                segments: None,
+            },
+            captures: route_match.captures,
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&routed_transcript).unwrap()).unwrap();
+        assert_eq!(json["transcript"], "Say hello");
+        assert_eq!(json["wake_word"], "Hey Hopper");
+        assert_eq!(json["captures"]["transcript"], "hello");
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let router = TranscriptRouter::new(&[
+            route(
+                "dance",
+                Some(r"(?i)dance (?:for )?(?P<duration>\d+) seconds"),
+                &[],
+            ),
+            route("any_dance", None, &["dance"]),
+            route("fallback", None, &[]),
+        ])
+        .unwrap();
+        let dance = router
+            .route("Hey Hopper", "Dance for 10 seconds please")
+            .unwrap();
+        assert_eq!(dance.name, "dance");
+        assert_eq!(dance.topic, "hopper/command/dance");
+        assert_eq!(dance.captures.get("duration").unwrap(), "10");
+
+        // pattern miss falls through to next route
+        assert_eq!(
+            router.route("Hey Hopper", "Can you DANCE?").unwrap().name,
+            "any_dance"
+        );
+        // keywords only match whole words
+        assert_eq!(
+            router.route("Hey Hopper", "What a dancer").unwrap().name,
+            "fallback"
+        );
+    }
+
+    #[test]
+    fn wake_word_filter() {
+        let mut music = route("music", None, &[]);
+        music.wake_words = vec![String::from("Songbird")];
+        let router = TranscriptRouter::new(&[music]).unwrap();
+        assert!(router.route("wintermute", "play jazz").is_none());
+        assert_eq!(router.route("songbird", "").unwrap().name, "music");
+    }
+
+    #[test]
+    fn wake_word_filter_from_config() {
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(
+                include_str!("../config/settings.yaml"),
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize::<WakewordConfig>()
+            .unwrap();
+        // detections carry the keyword name as loaded from keyword_paths
+        let wake_word = config
+            .picovoice
+            .keyword_paths
+            .iter()
+            .flatten()
+            .map(|(keyword, _)| keyword.as_str())
+            .find(|keyword| keyword.eq_ignore_ascii_case("Hey Hopper"))
+            .unwrap();
+        let router = TranscriptRouter::new(&config.transcript_routes).unwrap();
+        let dance = router.route(wake_word, "Dance for 5 seconds").unwrap();
+        assert_eq!(dance.name, "dance");
+        assert_eq!(dance.captures.get("seconds").unwrap(), "5");
+    }
+
+    #[test]
+    fn invalid_pattern_names_route() {
+        let err = TranscriptRouter::new(&[route("broken", Some("(unclosed"), &[])])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("broken"));
+    }
+}
@@ -0,0 +1,373 @@
+use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        AudioInput, AudioResponseFormat, CreateTranscriptionRequestArgs, TimestampGranularity,
+    },
+    Client as OpenAiClient,
+};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{
+    audio_encoding::AudioFormat,
+    configuration::TranscriptionConfig,
+    messages::{AudioSample, TranscriptSegment, TranscriptionErrorCategory},
+    VOICE_TO_TEXT_TRANSCRIBE_MODEL,
+};
+
+/// Language names returned by Whisper verbose responses and their ISO-639-1 codes
+const WHISPER_LANGUAGES: [(&str, &str); 98] = [
+    ("english", "en"),
+    ("chinese", "zh"),
+    ("german", "de"),
+    ("spanish", "es"),
+    ("russian", "ru"),
+    ("korean", "ko"),
+    ("french", "fr"),
+    ("japanese", "ja"),
+    ("portuguese", "pt"),
+    ("turkish", "tr"),
+    ("polish", "pl"),
+    ("catalan", "ca"),
+    ("dutch", "nl"),
+    ("arabic", "ar"),
+    ("swedish", "sv"),
+    ("italian", "it"),
+    ("indonesian", "id"),
+    ("hindi", "hi"),
+    ("finnish", "fi"),
+    ("vietnamese", "vi"),
+    ("hebrew", "he"),
+    ("ukrainian", "uk"),
+    ("greek", "el"),
+    ("malay", "ms"),
+    ("czech", "cs"),
+    ("romanian", "ro"),
+    ("danish", "da"),
+    ("hungarian", "hu"),
+    ("tamil", "ta"),
+    ("norwegian", "no"),
+    ("thai", "th"),
+    ("urdu", "ur"),
+    ("croatian", "hr"),
+    ("bulgarian", "bg"),
+    ("lithuanian", "lt"),
+    ("latin", "la"),
+    ("maori", "mi"),
+    ("malayalam", "ml"),
+    ("welsh", "cy"),
+    ("slovak", "sk"),
+    ("telugu", "te"),
+    ("persian", "fa"),
+    ("latvian", "lv"),
+    ("bengali", "bn"),
+    ("serbian", "sr"),
+    ("azerbaijani", "az"),
+    ("slovenian", "sl"),
+    ("kannada", "kn"),
+    ("estonian", "et"),
+    ("macedonian", "mk"),
+    ("breton", "br"),
+    ("basque", "eu"),
+    ("icelandic", "is"),
+    ("armenian", "hy"),
+    ("nepali", "ne"),
+    ("mongolian", "mn"),
+    ("bosnian", "bs"),
+    ("kazakh", "kk"),
+    ("albanian", "sq"),
+    ("swahili", "sw"),
+    ("galician", "gl"),
+    ("marathi", "mr"),
+    ("punjabi", "pa"),
+    ("sinhala", "si"),
+    ("khmer", "km"),
+    ("shona", "sn"),
+    ("yoruba", "yo"),
+    ("somali", "so"),
+    ("afrikaans", "af"),
+    ("occitan", "oc"),
+    ("georgian", "ka"),
+    ("belarusian", "be"),
+    ("tajik", "tg"),
+    ("sindhi", "sd"),
+    ("gujarati", "gu"),
+    ("amharic", "am"),
+    ("yiddish", "yi"),
+    ("lao", "lo"),
+    ("uzbek", "uz"),
+    ("faroese", "fo"),
+    ("haitian creole", "ht"),
+    ("pashto", "ps"),
+    ("turkmen", "tk"),
+    ("nynorsk", "nn"),
+    ("maltese", "mt"),
+    ("sanskrit", "sa"),
+    ("luxembourgish", "lb"),
+    ("myanmar", "my"),
+    ("tibetan", "bo"),
+    ("tagalog", "tl"),
+    ("malagasy", "mg"),
+    ("assamese", "as"),
+    ("tatar", "tt"),
+    ("lingala", "ln"),
+    ("hausa", "ha"),
+    ("bashkir", "ba"),
+    ("javanese", "jv"),
+    ("sundanese", "su"),
+];
+
+/// Verbose responses name the language while requests use ISO-639-1 codes
+///
+/// Unknown languages are passed through
+fn language_code(language: &str) -> String {
+    let language = language.trim().to_lowercase();
+    WHISPER_LANGUAGES
+        .iter()
+        .find(|(name, _)| *name == language)
+        .map(|(_, code)| (*code).to_owned())
+        .unwrap_or(language)
+}
+
+#[derive(Error, Debug)]
+pub enum TranscriptionError {
+    #[error("Transcription timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("OpenAI error {0:?}")]
+    OpenAiError(#[from] OpenAIError),
+    #[error("Failed to encode audio {0:?}")]
+    AudioEncodingError(anyhow::Error),
+    #[error("Recording wasn't transcribed within maximum queue age of {0:?}")]
+    Stale(Duration),
+}
+
+impl TranscriptionError {
+    pub fn category(&self) -> TranscriptionErrorCategory {
+        match self {
+            TranscriptionError::Timeout(_) => TranscriptionErrorCategory::Timeout,
+            TranscriptionError::Stale(_) => TranscriptionErrorCategory::Stale,
+            TranscriptionError::OpenAiError(OpenAIError::Reqwest(_)) => {
+                TranscriptionErrorCategory::Network
+            }
+            TranscriptionError::OpenAiError(OpenAIError::ApiError(_)) => {
+                TranscriptionErrorCategory::Api
+            }
+            TranscriptionError::OpenAiError(_) | TranscriptionError::AudioEncodingError(_) => {
+                TranscriptionErrorCategory::Other
+            }
+        }
+    }
+
+    /// Errors that might go away if the request is repeated
+    ///
+    /// API errors are not retried because the client already retries rate limits and server errors
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.category(),
+            TranscriptionErrorCategory::Timeout | TranscriptionErrorCategory::Network
+        )
+    }
+}
+
+pub struct Transcription {
+    pub text: String,
+    /// ISO-639-1 code of detected language with verbose responses, otherwise the requested language
+    pub language: Option<String>,
+    /// Following fields are only available with verbose responses
+    pub duration: Option<f32>,
+    pub segments: Option<Vec<TranscriptSegment>>,
+}
+
+impl Transcription {
+    /// Whisper likes to hallucinate text for silent recordings
+    /// These segments tend to have high no speech probability
+    /// Verbose responses without any segment are silence too
+    pub fn is_likely_silence(&self, no_speech_probability_threshold: f32) -> bool {
+        match &self.segments {
+            Some(segments) => segments
+                .iter()
+                .all(|segment| segment.no_speech_prob > no_speech_probability_threshold),
+            None => false,
+        }
+    }
+}
+
+pub struct Transcriber {
+    open_ai_client: OpenAiClient<OpenAIConfig>,
+    config: TranscriptionConfig,
+    audio_format: AudioFormat,
+}
+
+impl Transcriber {
+    pub fn new(
+        open_ai_client: OpenAiClient<OpenAIConfig>,
+        config: TranscriptionConfig,
+        audio_format: AudioFormat,
+    ) -> Self {
+        Self {
+            open_ai_client,
+            config,
+            audio_format,
+        }
+    }
+
+    /// Transcribe audio sample retrying transient failures
+    ///
+    /// Language is detected if not provided
+    pub async fn transcribe(
+        &self,
+        audio_sample: &AudioSample,
+        system_prompt: &str,
+        language: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError> {
+        let audio_file = audio_sample
+            .encode(self.audio_format)
+            .map_err(TranscriptionError::AudioEncodingError)?;
+
+        let mut retry_delay = self.config.retry_backoff();
+        let mut attempt = 0;
+        loop {
+            match self
+                .transcribe_once(audio_file.clone(), system_prompt, language)
+                .await
+            {
+                Ok(transcript) => return Ok(transcript),
+                Err(err) if err.is_transient() && attempt < self.config.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Transcription attempt {} failed with {:?}. Retrying in {:?}",
+                        attempt, err, retry_delay
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn transcribe_once(
+        &self,
+        audio_file: Vec<u8>,
+        system_prompt: &str,
+        language: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError> {
+        let audio_input = AudioInput::from_vec_u8(
+            format!("recorded.{}", self.audio_format.file_extension()),
+            audio_file,
+        );
+
+        let mut request = CreateTranscriptionRequestArgs::default();
+        request
+            .file(audio_input)
+            .model(VOICE_TO_TEXT_TRANSCRIBE_MODEL)
+            .prompt(system_prompt);
+        if let Some(language) = language {
+            request.language(language);
+        }
+
+        info!("Sending transcription request");
+        // only verbose response includes detected language
+        if self.config.verbose || language.is_none() {
+            let request = request
+                .response_format(AudioResponseFormat::VerboseJson)
+                .timestamp_granularities(vec![TimestampGranularity::Segment])
+                .build()?;
+            let response = tokio::time::timeout(
+                self.config.timeout(),
+                self.open_ai_client.audio().transcribe_verbose_json(request),
+            )
+            .await
+            .map_err(|_| TranscriptionError::Timeout(self.config.timeout()))??;
+
+            let segments = response.segments.map(|segments| {
+                segments
+                    .into_iter()
+                    .map(|segment| TranscriptSegment {
+                        start: segment.start,
+                        end: segment.end,
+                        text: segment.text,
+                        avg_logprob: segment.avg_logprob,
+                        no_speech_prob: segment.no_speech_prob,
+                    })
+                    .collect()
+            });
+
+            Ok(Transcription {
+                text: response.text,
+                language: Some(language_code(&response.language)),
+                duration: Some(response.duration),
+                segments,
+            })
+        } else {
+            let request = request.build()?;
+            let response = tokio::time::timeout(
+                self.config.timeout(),
+                self.open_ai_client.audio().transcribe(request),
+            )
+            .await
+            .map_err(|_| TranscriptionError::Timeout(self.config.timeout()))??;
+
+            Ok(Transcription {
+                text: response.text,
+                language: language.map(str::to_owned),
+                duration: None,
+                segments: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbose_language_names_become_codes() {
+        assert_eq!(language_code("english"), "en");
+        assert_eq!(language_code("Haitian Creole"), "ht");
+        assert_eq!(language_code("javanese"), "jv");
+        // configured codes are already normalized
+        assert_eq!(language_code("cs"), "cs");
+        assert_eq!(language_code("klingon"), "klingon");
+    }
+
+    #[test]
+    fn language_codes_are_unique() {
+        let mut codes: Vec<_> = WHISPER_LANGUAGES.iter().map(|(_, code)| *code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), WHISPER_LANGUAGES.len());
+        assert!(codes.iter().all(|code| code.len() == 2));
+    }
+
+    fn segment(no_speech_prob: f32) -> TranscriptSegment {
+        TranscriptSegment {
+            start: 0.0,
+            end: 1.0,
+            text: String::from("Thank you."),
+            avg_logprob: -0.5,
+            no_speech_prob,
+        }
+    }
+
+    #[test]
+    fn silence_requires_every_segment() {
+        let transcription = |segments: Option<Vec<TranscriptSegment>>| Transcription {
+            text: String::from("Thank you."),
+            language: None,
+            duration: None,
+            segments,
+        };
+        assert!(transcription(Some(vec![segment(0.9), segment(0.8)])).is_likely_silence(0.6));
+        assert!(!transcription(Some(vec![segment(0.9), segment(0.1)])).is_likely_silence(0.6));
+        // without verbose response nothing is known about silence
+        assert!(!transcription(None).is_likely_silence(0.6));
+        // Whisper returns no segments when it heard nothing
+        assert!(transcription(Some(vec![])).is_likely_silence(0.6));
+        // threshold itself isn't enough
+        assert!(!transcription(Some(vec![segment(0.6)])).is_likely_silence(0.6));
+    }
+}
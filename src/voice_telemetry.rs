@@ -0,0 +1,179 @@
+//! Downsampling of per frame voice probability for publishing
+//!
+//! Only affects what is published. Speech detection still uses every frame
+
+use std::time::{Duration, Instant};
+
+use crate::configuration::{
+    VoiceProbabilityAggregation, VoiceProbabilityPublishMode, VoiceProbabilityTelemetryConfig,
+};
+
+pub struct VoiceProbabilityAggregator {
+    interval: Duration,
+    aggregation: VoiceProbabilityAggregation,
+    mode: VoiceProbabilityPublishMode,
+    change_threshold: f32,
+    window_started: Option<Instant>,
+    max: f32,
+    sum: f32,
+    frames: u32,
+    last_published: Option<f32>,
+}
+
+impl VoiceProbabilityAggregator {
+    pub fn new(config: &VoiceProbabilityTelemetryConfig) -> Self {
+        Self {
+            interval: Duration::from_millis(config.interval_ms),
+            aggregation: config.aggregation,
+            mode: config.mode,
+            change_threshold: config.change_threshold,
+            window_started: None,
+            max: 0.0,
+            sum: 0.0,
+            frames: 0,
+            last_published: None,
+        }
+    }
+
+    /// Add probability of a frame
+    ///
+    /// Returns aggregated probability when the window is complete and it should be published
+    pub fn push(&mut self, probability: f32, now: Instant, recording: bool) -> Option<f32> {
+        if self.mode == VoiceProbabilityPublishMode::WhileRecording && !recording {
+            self.reset_window();
+            return None;
+        }
+
+        let window_started = *self.window_started.get_or_insert(now);
+        self.max = self.max.max(probability);
+        self.sum += probability;
+        self.frames += 1;
+        if now.duration_since(window_started) < self.interval {
+            return None;
+        }
+
+        let aggregated = match self.aggregation {
+            VoiceProbabilityAggregation::Max => self.max,
+            VoiceProbabilityAggregation::Mean => self.sum / self.frames as f32,
+        };
+        self.reset_window();
+
+        if self.mode == VoiceProbabilityPublishMode::OnChange {
+            if let Some(last_published) = self.last_published {
+                if (aggregated - last_published).abs() < self.change_threshold {
+                    return None;
+                }
+            }
+        }
+        self.last_published = Some(aggregated);
+        Some(aggregated)
+    }
+
+    fn reset_window(&mut self) {
+        self.window_started = None;
+        self.max = 0.0;
+        self.sum = 0.0;
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(32);
+
+    /// Published values when frames arrive every 32ms
+    fn push_all(
+        aggregator: &mut VoiceProbabilityAggregator,
+        probabilities: &[f32],
+        recording: bool,
+    ) -> Vec<f32> {
+        let start = Instant::now();
+        probabilities
+            .iter()
+            .enumerate()
+            .filter_map(|(index, probability)| {
+                aggregator.push(*probability, start + FRAME * index as u32, recording)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_frame_by_default() {
+        let mut aggregator = VoiceProbabilityAggregator::new(&Default::default());
+        assert_eq!(
+            push_all(&mut aggregator, &[0.1, 0.1, 0.9], false),
+            vec![0.1, 0.1, 0.9]
+        );
+    }
+
+    #[test]
+    fn window_includes_frame_at_interval() {
+        // frames at 0, 32 and 64ms all belong to the first 64ms window
+        let probabilities = [0.2, 0.8, 0.2, 0.0, 0.0, 0.6];
+        let mut max = VoiceProbabilityAggregator::new(&VoiceProbabilityTelemetryConfig {
+            interval_ms: 64,
+            aggregation: VoiceProbabilityAggregation::Max,
+            ..Default::default()
+        });
+        assert_eq!(push_all(&mut max, &probabilities, false), vec![0.8, 0.6]);
+
+        let mut mean = VoiceProbabilityAggregator::new(&VoiceProbabilityTelemetryConfig {
+            interval_ms: 64,
+            aggregation: VoiceProbabilityAggregation::Mean,
+            ..Default::default()
+        });
+        assert_eq!(push_all(&mut mean, &probabilities, false), vec![0.4, 0.2]);
+    }
+
+    #[test]
+    fn late_frame_closes_window_without_carrying_over() {
+        let mut aggregator = VoiceProbabilityAggregator::new(&VoiceProbabilityTelemetryConfig {
+            interval_ms: 1000,
+            aggregation: VoiceProbabilityAggregation::Max,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(aggregator.push(0.9, start, false), None);
+        // listener stalled for longer than the interval
+        let late = start + Duration::from_secs(5);
+        assert_eq!(aggregator.push(0.1, late, false), Some(0.9));
+        // new window starts at the late frame
+        assert_eq!(aggregator.push(0.2, late + FRAME, false), None);
+        assert_eq!(
+            aggregator.push(0.3, late + FRAME + Duration::from_secs(1), false),
+            Some(0.3)
+        );
+    }
+
+    #[test]
+    fn window_is_discarded_when_recording_stops() {
+        let mut aggregator = VoiceProbabilityAggregator::new(&VoiceProbabilityTelemetryConfig {
+            interval_ms: 64,
+            aggregation: VoiceProbabilityAggregation::Max,
+            mode: VoiceProbabilityPublishMode::WhileRecording,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(aggregator.push(0.9, start, true), None);
+        assert_eq!(aggregator.push(0.9, start + FRAME, false), None);
+        // 0.9 from the previous recording isn't part of the new window
+        assert_eq!(aggregator.push(0.1, start + FRAME * 2, true), None);
+        assert_eq!(aggregator.push(0.2, start + FRAME * 4, true), Some(0.2));
+    }
+
+    #[test]
+    fn on_change_compares_with_last_published_value() {
+        let mut aggregator = VoiceProbabilityAggregator::new(&VoiceProbabilityTelemetryConfig {
+            mode: VoiceProbabilityPublishMode::OnChange,
+            change_threshold: 0.1,
+            ..Default::default()
+        });
+        // slow drift is published once it adds up to the threshold
+        assert_eq!(
+            push_all(&mut aggregator, &[0.0, 0.06, 0.12, 0.17, 0.5, 0.45], false),
+            vec![0.0, 0.12, 0.5]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::VecDeque,
     io::Cursor,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -12,10 +13,26 @@ use async_openai::{
 };
 use tracing::{error, info};
 
-use crate::{VOICE_TO_TEXT_TRANSCRIBE_MODEL, VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE};
+use crate::{
+    configuration::ValidationBackendConfig, template_validation::TemplateValidator,
+    transcript_filter::normalize_transcript, VOICE_TO_TEXT_TRANSCRIBE_MODEL,
+};
 
 const AUDIO_SAMPLE_RETENTION_PERIOD: Duration = Duration::from_secs(5);
 
+/// Result of validation backend
+pub struct ValidationOutcome {
+    /// Wake word was found
+    pub valid: bool,
+    /// Backend specific score compared with threshold
+    pub score: f32,
+    /// Transcript of the audio if backend transcribes
+    pub transcript: Option<String>,
+}
+
+/// Resolves to validation outcome or error if validation failed
+pub type ValidationReceiver = tokio::sync::oneshot::Receiver<anyhow::Result<ValidationOutcome>>;
+
 /// Returned from the validation function
 /// Not used in this module but I didn't know where to put it
 pub enum ValidationStatus {
@@ -23,25 +40,68 @@ pub enum ValidationStatus {
     NotValid,
     /// validation still ongoing
     Processing,
-    /// validation is not available. Likely already finished and either failed or succeeded
-    /// Either case we don't care because the method would have cleaned up if it was a failure
+    /// No validation is pending. It was skipped, disabled for the keyword, failed to start
+    /// or its result was already handled
     NotAvailable,
     /// Validation successful
     Valid,
 }
 
+/// Audio and expectations of single validation
+pub struct ValidationRequest {
+    pub wake_word: String,
+    /// Recent audio containing the wake word
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    /// Language is detected by Whisper if not provided
+    pub language: Option<String>,
+    /// Spellings of the wake word in the selected language
+    pub spellings: Vec<String>,
+    pub similarity_threshold: f32,
+}
+
+/// Second stage wake word verification
+pub trait ValidationBackend: Send + Sync {
+    /// Start validation without blocking the caller
+    fn validate(&self, request: ValidationRequest) -> anyhow::Result<ValidationReceiver>;
+}
+
+/// Built once at startup and shared by rebuilt listeners
+///
+/// Template enrollment is read here so a missing directory, invalid sample
+/// or validated keyword without samples fails startup
+pub fn build_validation_backend(
+    config: &ValidationBackendConfig,
+    validated_keywords: &[String],
+    sample_rate: u32,
+    open_ai_client: Client<OpenAIConfig>,
+) -> anyhow::Result<Arc<dyn ValidationBackend>> {
+    Ok(match config {
+        ValidationBackendConfig::Whisper => Arc::new(WhisperValidator::new(open_ai_client)),
+        ValidationBackendConfig::Template {
+            enrollment_dir,
+            score_threshold,
+        } => Arc::new(TemplateValidator::from_directory(
+            enrollment_dir,
+            validated_keywords,
+            sample_rate,
+            *score_threshold,
+        )?),
+    })
+}
+
 pub struct WakeWordValidator {
     buffer: AudioBuffer,
     sample_rate: u32,
-    open_ai_client: Client<OpenAIConfig>,
+    backend: Arc<dyn ValidationBackend>,
 }
 
 impl WakeWordValidator {
-    pub fn new(open_ai_client: Client<OpenAIConfig>, sample_rate: u32) -> Self {
+    pub fn new(backend: Arc<dyn ValidationBackend>, sample_rate: u32) -> Self {
         Self {
             buffer: Default::default(),
             sample_rate,
-            open_ai_client,
+            backend,
         }
     }
 
@@ -49,43 +109,96 @@ impl WakeWordValidator {
         self.buffer.insert(now, sample);
     }
 
+    /// Recent audio used for validation
+    pub fn window(&self) -> Vec<i16> {
+        self.buffer.contents()
+    }
+
+    /// Audio of the last `duration` before `now` excluding frames inserted at `now`
+    pub fn audio_before(&self, now: Instant, duration: Duration) -> Vec<i16> {
+        self.buffer.contents_before(now, duration)
+    }
+
+    /// `spellings` are spellings of the wake word in the selected language
+    /// Language is detected by Whisper if not provided
     pub fn contains_wakeword(
         &self,
         wakeword: &str,
-    ) -> anyhow::Result<tokio::sync::oneshot::Receiver<bool>> {
-        let wav_file = self.buffer.contents_to_wav(self.sample_rate)?;
+        language: Option<&str>,
+        spellings: Vec<String>,
+        similarity_threshold: f32,
+    ) -> anyhow::Result<ValidationReceiver> {
+        info!("starting validation for wakeword {:?}", wakeword);
+        self.backend.validate(ValidationRequest {
+            wake_word: wakeword.to_owned(),
+            samples: self.buffer.contents(),
+            sample_rate: self.sample_rate,
+            language: language.map(str::to_owned),
+            spellings,
+            similarity_threshold,
+        })
+    }
+}
+
+/// Validates wake word by transcribing audio using Whisper
+pub struct WhisperValidator {
+    open_ai_client: Client<OpenAIConfig>,
+}
+
+impl WhisperValidator {
+    pub fn new(open_ai_client: Client<OpenAIConfig>) -> Self {
+        Self { open_ai_client }
+    }
+}
+
+impl ValidationBackend for WhisperValidator {
+    fn validate(&self, request: ValidationRequest) -> anyhow::Result<ValidationReceiver> {
+        let wav_file = samples_to_wav(&request.samples, request.sample_rate)?;
         let audio_input = AudioInput::from_vec_u8(String::from("recorded.wav"), wav_file);
 
-        let request = CreateTranscriptionRequestArgs::default()
+        let mut transcription_request = CreateTranscriptionRequestArgs::default();
+        transcription_request
             .file(audio_input)
             .model(VOICE_TO_TEXT_TRANSCRIBE_MODEL)
-            .language(VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE)
             .prompt(format!(
                 "This sample might contain the wake word {}",
-                wakeword
-            ))
-            .build()?;
+                request.wake_word
+            ));
+        if let Some(language) = &request.language {
+            transcription_request.language(language);
+        }
+        let transcription_request = transcription_request.build()?;
 
         // execute future
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         tokio::spawn({
             let open_ai_client = self.open_ai_client.clone();
-            let wakeword = wakeword.to_owned();
             async move {
-                info!("starting validation for wakeword {:?}", &wakeword);
-                match open_ai_client.audio().transcribe(request).await {
+                let wakeword = request.wake_word;
+                match open_ai_client
+                    .audio()
+                    .transcribe(transcription_request)
+                    .await
+                {
                     Ok(response) => {
                         info!(
                             "Transcribe for wakeword: {:?} returned {:?}",
                             wakeword, response.text
                         );
-                        let contains = response.text.to_ascii_lowercase().contains(&wakeword);
+                        let similarity = wake_word_similarity(&response.text, &request.spellings);
+                        info!("Wakeword {:?} similarity {:.2}", wakeword, similarity);
+                        let outcome = ValidationOutcome {
+                            valid: similarity >= request.similarity_threshold,
+                            score: similarity,
+                            transcript: Some(response.text),
+                        };
                         // ignore error because we don't care if we failed to send
-                        _ = tx.send(contains);
+                        _ = tx.send(Ok(outcome));
                     }
                     Err(err) => {
                         error!("Failed to transcribe wakeword buffer {:?}", err);
+                        _ = tx.send(Err(err.into()));
                     }
                 }
             }
@@ -95,8 +208,58 @@ impl WakeWordValidator {
     }
 }
 
+/// Highest similarity between 0 and 1 of any spelling to a run of words in the transcript
+///
+/// Whisper spells wake words in many ways ("Hey, Hopper", "Hay Hopper", "hey-hopper")
+/// so punctuation and spaces are ignored and small differences are tolerated
+pub fn wake_word_similarity(transcript: &str, spellings: &[String]) -> f32 {
+    let transcript = normalize_transcript(transcript);
+    let words: Vec<&str> = transcript.split_whitespace().collect();
+    let mut best: f32 = 0.0;
+    for spelling in spellings {
+        let spelling = normalize_transcript(spelling);
+        let spelling_words = spelling.split_whitespace().count();
+        let spelling: Vec<char> = spelling.chars().filter(|c| !c.is_whitespace()).collect();
+        if spelling.is_empty() {
+            continue;
+        }
+        // words might be split or merged differently than in the spelling
+        let min_window = spelling_words.saturating_sub(1).max(1);
+        for window_size in min_window..=spelling_words + 1 {
+            for window in words.windows(window_size.min(words.len()).max(1)) {
+                let candidate: Vec<char> = window.concat().chars().collect();
+                best = best.max(similarity(&spelling, &candidate));
+            }
+        }
+    }
+    best
+}
+
+fn similarity(a: &[char], b: &[char]) -> f32 {
+    let max_len = a.len().max(b.len());
+    if max_len == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein_distance(a, b) as f32 / max_len as f32
+}
+
+fn levenshtein_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a_char) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Audio of the last few seconds
 #[derive(Debug, Default)]
-struct AudioBuffer {
+pub struct AudioBuffer {
     samples: VecDeque<AudioSample>,
 }
 
@@ -107,7 +270,7 @@ struct AudioSample {
 }
 
 impl AudioBuffer {
-    fn insert(&mut self, now: Instant, sample: &[i16]) {
+    pub fn insert(&mut self, now: Instant, sample: &[i16]) {
         // drain old
         while self.samples.front().is_some_and(|sample| {
             now.checked_duration_since(sample.time).unwrap_or_default()
@@ -122,35 +285,47 @@ impl AudioBuffer {
         });
     }
 
-    fn contents_to_wav(&self, sample_rate: u32) -> anyhow::Result<Vec<u8>> {
-        let sample: Vec<i16> = self
-            .samples
+    fn contents(&self) -> Vec<i16> {
+        self.samples
             .iter()
-            .flat_map(|sample| sample.sample.clone())
-            .collect();
-
-        let wavspec = hound::WavSpec {
-            channels: 1,
-            sample_rate,
-            bits_per_sample: 16,
-            sample_format: hound::SampleFormat::Int,
-        };
+            .flat_map(|sample| sample.sample.iter().copied())
+            .collect()
+    }
 
-        let mut file = vec![];
+    fn contents_before(&self, now: Instant, duration: Duration) -> Vec<i16> {
+        self.samples
+            .iter()
+            .filter(|sample| {
+                sample.time < now
+                    && now.checked_duration_since(sample.time).unwrap_or_default() <= duration
+            })
+            .flat_map(|sample| sample.sample.iter().copied())
+            .collect()
+    }
+}
 
-        {
-            let cursor = Cursor::new(&mut file);
-            let mut writer = hound::WavWriter::new(cursor, wavspec)
-                .context("Failed to open output audio file")?;
-            for sample in sample {
-                writer
-                    .write_sample(sample)
-                    .context("Failed to write sample")?;
-            }
+fn samples_to_wav(samples: &[i16], sample_rate: u32) -> anyhow::Result<Vec<u8>> {
+    let wavspec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut file = vec![];
+
+    {
+        let cursor = Cursor::new(&mut file);
+        let mut writer =
+            hound::WavWriter::new(cursor, wavspec).context("Failed to open output audio file")?;
+        for sample in samples {
+            writer
+                .write_sample(*sample)
+                .context("Failed to write sample")?;
         }
-
-        Ok(file)
     }
+
+    Ok(file)
 }
 
 #[cfg(test)]
@@ -181,6 +356,55 @@ mod tests {
         assert_eq!(buffer.samples.len(), 1);
     }
 
+    const THRESHOLD: f32 = 0.75;
+
+    fn matches(transcript: &str, spellings: &[&str]) -> bool {
+        let spellings: Vec<String> = spellings.iter().map(|s| s.to_string()).collect();
+        wake_word_similarity(transcript, &spellings) >= THRESHOLD
+    }
+
+    #[test]
+    fn levenshtein() {
+        let distance = |a: &str, b: &str| {
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            levenshtein_distance(&a, &b)
+        };
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("hopper", "hopper"), 0);
+    }
+
+    #[test]
+    fn common_whisper_spellings() {
+        let spellings = ["Hey Hopper"];
+        assert!(matches("Hey Hopper", &spellings));
+        assert!(matches("Hey, Hopper!", &spellings));
+        assert!(matches("hey hopper sit down", &spellings));
+        assert!(matches("Hay Hopper.", &spellings));
+        assert!(matches("Hey Harper, stand up", &spellings));
+        assert!(matches("Heyhopper", &spellings));
+        assert!(matches("Hey hop-per", &spellings));
+        assert!(matches("Wintermute", &["Wintermute"]));
+        assert!(matches("Winter mute.", &["Wintermute"]));
+        assert!(matches("Song bird, play jazz", &["Songbird"]));
+    }
+
+    #[test]
+    fn rejects_different_words() {
+        assert!(!matches("", &["Hey Hopper"]));
+        assert!(!matches("Thank you for watching", &["Hey Hopper"]));
+        assert!(!matches("Hello there", &["Hey Hopper"]));
+        assert!(!matches("Hey", &["Hey Hopper"]));
+        assert!(!matches("Bumblebee", &["Wintermute"]));
+    }
+
+    #[test]
+    fn aliases() {
+        assert!(!matches("Hej Hopře", &["Hey Hopper"]));
+        assert!(matches("Hej Hopře", &["Hey Hopper", "hej hopře"]));
+    }
+
     #[test]
     fn buffer_ordering() {
         let start = Instant::now();
@@ -200,4 +424,22 @@ mod tests {
         assert_eq!(&buffer.samples[1].sample, &[1]);
         assert_eq!(&buffer.samples[2].sample, &[2]);
     }
+
+    #[test]
+    fn contents_before_excludes_current_frame() {
+        let start = Instant::now();
+        let frame = Duration::from_millis(32);
+        let mut buffer = AudioBuffer::default();
+        for index in 0..5 {
+            buffer.insert(start + frame * index, &[index as i16]);
+        }
+        let now = start + frame * 4;
+        assert_eq!(buffer.contents_before(now, frame * 2), vec![2, 3]);
+        assert_eq!(
+            buffer.contents_before(now, Duration::ZERO),
+            Vec::<i16>::new()
+        );
+        // nothing recorded before first frame
+        assert_eq!(buffer.contents_before(start, frame), Vec::<i16>::new());
+    }
 }